@@ -0,0 +1,212 @@
+use crate::*;
+
+/// How an aggregate combines the values of its variable in each group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AggregateOp {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+impl AggregateOp {
+    fn from_symbol(sym: Symbol) -> Option<Self> {
+        match sym.as_str() {
+            "count" => Some(Self::Count),
+            "sum" => Some(Self::Sum),
+            "min" => Some(Self::Min),
+            "max" => Some(Self::Max),
+            _ => None,
+        }
+    }
+
+    /// The type of the result, if values of this type can be aggregated.
+    fn output_type(self, ty: &Type) -> Option<Type> {
+        match (self, ty) {
+            (Self::Count, _) => Some(Type::NumType(NumType::I64)),
+            (Self::Sum | Self::Min | Self::Max, Type::NumType(_)) => Some(ty.clone()),
+            (Self::Min | Self::Max, Type::String) => Some(ty.clone()),
+            _ => None,
+        }
+    }
+
+    /// The result for a group with no matches, if there is one.
+    fn empty(self, ty: &Type) -> Option<Value> {
+        match (self, ty) {
+            (Self::Count, _) | (Self::Sum, Type::NumType(NumType::I64)) => Some(Value::from(0)),
+            (Self::Sum, _) => Some(Value::from(BigRational::from_integer(0.into()))),
+            (Self::Min | Self::Max, _) => None,
+        }
+    }
+
+    /// Combines the values from a group's matches, of which there is at least one,
+    /// returning None if there's no result, like when an i64 sum overflows.
+    pub(crate) fn reduce(self, values: Vec<Value>) -> Option<Value> {
+        match self {
+            Self::Count => Some(Value::from(values.len() as i64)),
+            _ => {
+                let mut values = values.into_iter();
+                let first = values.next();
+                values.try_fold(first?, |a, b| self.combine(a, b))
+            }
+        }
+    }
+
+    fn combine(self, a: Value, b: Value) -> Option<Value> {
+        use ValueInner::*;
+        let ordering = match (&a.0, &b.0) {
+            (I64(x), I64(y)) if self == Self::Sum => return x.checked_add(*y).map(Value::from),
+            (Rational(x), Rational(y)) if self == Self::Sum => return Some(Value::from(x + y)),
+            (String(x), String(y)) => x.as_str().cmp(y.as_str()),
+            // numbers of the same type are ordered by value
+            _ => a.cmp(&b),
+        };
+        match (self, ordering.is_le()) {
+            (Self::Min, true) | (Self::Max, false) => Some(a),
+            _ => Some(b),
+        }
+    }
+}
+
+/// An aggregate in a query, over the matches of its own query,
+/// grouped by the variables that query shares with the outer one.
+#[derive(Clone, Debug)]
+pub(crate) struct Aggregate {
+    pub(crate) expr: Expr,
+    pub(crate) op: AggregateOp,
+    /// The variable of `query` being aggregated.
+    pub(crate) var: Symbol,
+    pub(crate) query: Query,
+    pub(crate) keys: Vec<Symbol>,
+    /// The variable of the outer query holding the result.
+    pub(crate) output: Symbol,
+    pub(crate) ty: Type,
+    /// The result for a group with no matches, if there is one.
+    pub(crate) empty: Option<Value>,
+}
+
+/// Turns an expression in an aggregate's body back into the fact it was parsed from.
+fn expr_to_fact(expr: &Expr) -> Fact {
+    match expr {
+        Expr::Call(span, op, args) if op.as_str() == "=" => Fact::Eq(*span, args.clone()),
+        Expr::Call(span, op, args) if op.as_str() == "not" && args.len() == 1 => {
+            Fact::Not(*span, Box::new(expr_to_fact(&args[0])))
+        }
+        _ => Fact::Fact(expr.clone()),
+    }
+}
+
+impl EGraph {
+    /// Whether `expr` is an aggregate like `(count y (edge x y))`, returning its
+    /// operator, variable, and body. A call whose arguments after the first
+    /// aren't all facts is a call to a primitive like `min` instead.
+    fn as_aggregate<'e>(&self, expr: &'e Expr) -> Option<(AggregateOp, Symbol, &'e [Expr])> {
+        let Expr::Call(_, op, args) = expr else {
+            return None;
+        };
+        if self.functions.contains_key(op) {
+            return None;
+        }
+        let op = AggregateOp::from_symbol(*op)?;
+        let (var, body) = args.split_first()?;
+        let is_fact = |e: &Expr| match e {
+            Expr::Call(_, f, _) if matches!(f.as_str(), "=" | "not") => true,
+            Expr::Call(_, f, _) => {
+                (self.functions.get(f)).is_some_and(|f| f.decl.schema.output == Type::Unit)
+            }
+            _ => false,
+        };
+        match var {
+            Expr::Var(_, var) if !body.is_empty() && body.iter().all(is_fact) => {
+                Some((op, *var, body))
+            }
+            _ => None,
+        }
+    }
+
+    /// Replaces the aggregates in the facts of a query with variables,
+    /// compiling each aggregate's body as a query of its own.
+    pub(crate) fn extract_aggregates(
+        &self,
+        facts: Vec<Fact>,
+    ) -> Result<(Vec<Fact>, Vec<Aggregate>), Error> {
+        // the variables outside of aggregates, which they're grouped by
+        let mut outer = HashSet::<Symbol>::default();
+        fn add_vars(egraph: &EGraph, expr: &Expr, outer: &mut HashSet<Symbol>) {
+            match expr {
+                Expr::Var(_, var) => {
+                    outer.insert(*var);
+                }
+                Expr::Call(_, _, args) if egraph.as_aggregate(expr).is_none() => {
+                    args.iter().for_each(|arg| add_vars(egraph, arg, outer))
+                }
+                _ => {}
+            }
+        }
+        for fact in &facts {
+            fact.for_each_expr(&mut |e| add_vars(self, e, &mut outer));
+        }
+
+        let mut aggregates = vec![];
+        let mut replace = |e: &Expr| self.replace_aggregates(e, &outer, &mut aggregates);
+        let facts = facts
+            .iter()
+            .map(|fact| match fact {
+                Fact::Eq(span, exprs) => Ok(Fact::Eq(
+                    *span,
+                    exprs.iter().map(&mut replace).collect::<Result<_, _>>()?,
+                )),
+                Fact::Fact(expr) => Ok(Fact::Fact(replace(expr)?)),
+                Fact::Not(..) => Ok(fact.clone()),
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok((facts, aggregates))
+    }
+
+    fn replace_aggregates(
+        &self,
+        expr: &Expr,
+        outer: &HashSet<Symbol>,
+        aggregates: &mut Vec<Aggregate>,
+    ) -> Result<Expr, Error> {
+        if let Some((op, var, body)) = self.as_aggregate(expr) {
+            let span = expr.span();
+            let query = self.compile_query(body.iter().map(expr_to_fact).collect())?;
+            let Some(var_ty) = query.types.get(&var) else {
+                return Err(TypeError::Unbound(var, span).into());
+            };
+            let ty = op.output_type(var_ty).ok_or_else(|| TypeError::Mismatch {
+                expr: expr.clone(),
+                expected: Type::NumType(NumType::I64),
+                actual: var_ty.clone(),
+                reason: "aggregate".into(),
+            })?;
+            let mut keys: Vec<Symbol> = (query.bindings.keys())
+                .filter(|k| **k != var && outer.contains(*k))
+                .copied()
+                .collect();
+            keys.sort_by_key(|k| k.as_str());
+            // not a valid identifier, so it can't clash with the user's variables
+            let output = Symbol::from(format!("__aggregate_{}", aggregates.len()));
+            aggregates.push(Aggregate {
+                expr: expr.clone(),
+                op,
+                var,
+                empty: op.empty(var_ty),
+                query,
+                keys,
+                output,
+                ty,
+            });
+            Ok(Expr::Var(span, output))
+        } else if let Expr::Call(span, op, args) = expr {
+            let args = args
+                .iter()
+                .map(|arg| self.replace_aggregates(arg, outer, aggregates))
+                .collect::<Result<_, _>>()?;
+            Ok(Expr::Call(*span, *op, args))
+        } else {
+            Ok(expr.clone())
+        }
+    }
+}
@@ -9,6 +9,7 @@ pub enum Literal {
     Int(i64),
     Rational(BigRational),
     String(Symbol),
+    Bool(bool),
     Unit,
 }
 
@@ -34,6 +35,7 @@ macro_rules! impl_from {
 
 impl_from!(Int(i64));
 impl_from!(String(Symbol));
+impl_from!(Bool(bool));
 
 impl Literal {
     pub fn to_value(&self) -> Value {
@@ -41,6 +43,7 @@ impl Literal {
             Literal::Int(i) => Value::from(*i),
             Literal::String(s) => Value::from(*s),
             Literal::Rational(r) => Value::from(r.clone()),
+            Literal::Bool(b) => Value::from(*b),
             Literal::Unit => Value(ValueInner::Unit),
         }
     }
@@ -52,6 +55,7 @@ impl Display for Literal {
             Literal::Int(i) => Display::fmt(i, f),
             Literal::String(s) => write!(f, "{s}"),
             Literal::Rational(r) => write!(f, "{}//{}", r.numer(), r.denom()),
+            Literal::Bool(b) => Display::fmt(b, f),
             Literal::Unit => write!(f, "()"),
         }
     }
@@ -59,32 +63,42 @@ impl Display for Literal {
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub enum Expr {
-    Lit(Literal),
-    Var(Symbol),
+    Lit(Span, Literal),
+    Var(Span, Symbol),
     // TODO make this its own type
-    Call(Symbol, Vec<Self>),
+    Call(Span, Symbol, Vec<Self>),
 }
 
 impl Expr {
     pub fn call(op: impl Into<Symbol>, children: impl IntoIterator<Item = Self>) -> Self {
-        Self::Call(op.into(), children.into_iter().collect())
+        Self::Call(Span::default(), op.into(), children.into_iter().collect())
     }
 
     pub fn lit(lit: impl Into<Literal>) -> Self {
-        Self::Lit(lit.into())
+        Self::Lit(Span::default(), lit.into())
+    }
+
+    pub fn var(var: impl Into<Symbol>) -> Self {
+        Self::Var(Span::default(), var.into())
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Lit(span, _) | Expr::Var(span, _) | Expr::Call(span, ..) => *span,
+        }
     }
 
     pub fn get_var(&self) -> Option<Symbol> {
         match self {
-            Expr::Var(v) => Some(*v),
+            Expr::Var(_, v) => Some(*v),
             _ => None,
         }
     }
 
     fn children(&self) -> &[Self] {
         match self {
-            Expr::Var(_) | Expr::Lit(_) => &[],
-            Expr::Call(_, children) => children,
+            Expr::Var(..) | Expr::Lit(..) => &[],
+            Expr::Call(_, _, children) => children,
         }
     }
 
@@ -105,9 +119,9 @@ impl Expr {
 impl Display for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Expr::Lit(lit) => Display::fmt(lit, f),
-            Expr::Var(var) => Display::fmt(var, f),
-            Expr::Call(op, args) => {
+            Expr::Lit(_, lit) => Display::fmt(lit, f),
+            Expr::Var(_, var) => Display::fmt(var, f),
+            Expr::Call(_, op, args) => {
                 write!(f, "({}", op)?;
                 for arg in args {
                     write!(f, " {}", arg)?;
@@ -18,6 +18,73 @@ use crate::*;
 mod expr;
 pub use expr::*;
 
+/// The byte range of a node in the source it was parsed from. An empty span
+/// doesn't point anywhere, like those of ASTs built in code.
+///
+/// Spans never affect comparisons, so ASTs built in code equal parsed ones.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Shows where the span is in `source` (read from `path`) with its line
+    /// and column, followed by the first line of the span underlined.
+    pub fn render(&self, path: &str, source: &str) -> String {
+        let start = self.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let line = &source[line_start..line_end];
+        let line_number = source[..line_start].matches('\n').count() + 1;
+        let column = source[line_start..start].chars().count();
+        let end = self.end.clamp(start, line_end);
+        let width = source[start..end].chars().count().max(1);
+
+        let margin = " ".repeat(line_number.to_string().len());
+        format!(
+            "{margin}--> {path}:{line_number}:{}\n{margin} |\n{line_number} | {line}\n{margin} | {}{}",
+            column + 1,
+            " ".repeat(column),
+            "^".repeat(width),
+        )
+    }
+}
+
+impl PartialEq for Span {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Span {}
+
+impl PartialOrd for Span {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Span {
+    fn cmp(&self, _other: &Self) -> std::cmp::Ordering {
+        std::cmp::Ordering::Equal
+    }
+}
+
+impl std::hash::Hash for Span {
+    fn hash<H: std::hash::Hasher>(&self, _state: &mut H) {}
+}
+
 #[derive(Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub struct Id(usize);
 
@@ -45,6 +112,20 @@ impl Display for Id {
     }
 }
 
+/// Which terms to extract from an e-class.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExtractMode {
+    /// The cheapest term, paying for a shared subterm every time it appears.
+    Tree,
+    /// This many of the cheapest terms, or all of them if there are fewer.
+    Variants(usize),
+    /// The cheapest term paying for each shared subterm once, written with `let`s.
+    /// Settles for the cheapest found within the time limit, if there is one.
+    Dag {
+        time_limit: Option<std::time::Duration>,
+    },
+}
+
 #[derive(Debug)]
 pub enum Command {
     Datatype {
@@ -56,26 +137,94 @@ pub enum Command {
     Rule(Rule),
     Rewrite(Rewrite),
     Action(Action),
-    Run(usize),
-    Extract(Expr),
+    Ruleset(Symbol),
+    Run(Schedule),
+    /// Choose the scheduler used by later runs, by name (`simple` or `backoff`).
+    SetScheduler {
+        name: Symbol,
+        match_limit: Option<usize>,
+        ban_length: Option<usize>,
+    },
+    /// Set the limits on later runs, keeping any cancellation flag.
+    SetLimits {
+        node_limit: Option<usize>,
+        size_limit: Option<usize>,
+        time_limit: Option<std::time::Duration>,
+    },
+    /// Extract the cheapest term equal to an expression, or its value if it
+    /// isn't an e-class.
+    Extract {
+        span: Span,
+        expr: Expr,
+        mode: ExtractMode,
+        /// Only extract terms made of these constructors.
+        only: Option<Vec<Symbol>>,
+        /// Also show the values of the functions other than constructors
+        /// that take the e-class.
+        with_functions: bool,
+    },
+    /// Show the statistics of every rule.
+    PrintStats,
+    /// Record a justification for every union, so equalities can be explained.
+    EnableProofs,
+    /// Explain why two terms are equal.
+    ExplainEq(Expr, Expr),
     // TODO: this could just become an empty query
     Check(Fact),
     ClearRules,
     Query(Vec<Fact>),
+    /// Show how a query (or the body of a rule) is compiled, planned, and run.
+    Explain(Vec<Fact>),
+}
+
+/// Says which rules to run, and in what order.
+#[derive(Clone, Debug)]
+pub enum Schedule {
+    /// Run a ruleset for at most this many iterations,
+    /// or until saturation if there is no limit.
+    Run {
+        ruleset: Symbol,
+        limit: Option<usize>,
+    },
+    Seq(Vec<Schedule>),
+    /// Run the schedule this many times, stopping early if a pass makes no updates.
+    Repeat(usize, Box<Schedule>),
+    /// Run the schedule until a pass makes no updates.
+    Saturate(Box<Schedule>),
+}
+
+impl Schedule {
+    /// Calls `f` on every ruleset that the schedule runs.
+    pub fn rulesets<E>(&self, f: &mut impl FnMut(Symbol) -> Result<(), E>) -> Result<(), E> {
+        match self {
+            Schedule::Run { ruleset, .. } => f(*ruleset),
+            Schedule::Seq(schedules) => schedules.iter().try_for_each(|s| s.rulesets(f)),
+            Schedule::Repeat(_, schedule) | Schedule::Saturate(schedule) => schedule.rulesets(f),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct FunctionDecl {
+    pub span: Span,
     pub name: Symbol,
     pub schema: Schema,
     pub default: Option<Expr>,
     pub merge: Option<Expr>,
+    /// The cost of each of its nodes when extracting, if it returns an e-class.
+    /// Defaults to 1.
+    pub cost: Option<usize>,
+    /// Whether to leave its nodes out of extracted terms.
+    pub unextractable: bool,
 }
 
 #[derive(Clone, Debug)]
 pub struct Variant {
+    pub span: Span,
     pub name: Symbol,
     pub types: Vec<Type>,
+    pub cost: Option<usize>,
+    pub unextractable: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -84,6 +233,7 @@ pub enum Type {
     Sort(Symbol),
     NumType(NumType),
     String,
+    Bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -108,6 +258,7 @@ impl Display for Type {
             Type::Sort(s) => Display::fmt(s, f),
             Type::NumType(t) => Display::fmt(t, f),
             Type::String => write!(f, "String"),
+            Type::Bool => write!(f, "bool"),
             Type::Unit => write!(f, "Unit"),
         }
     }
@@ -126,8 +277,9 @@ pub struct Schema {
 }
 
 impl FunctionDecl {
-    pub fn relation(name: Symbol, input: Vec<Type>) -> Self {
+    pub fn relation(span: Span, name: Symbol, input: Vec<Type>) -> Self {
         Self {
+            span,
             name,
             schema: Schema {
                 input,
@@ -135,6 +287,8 @@ impl FunctionDecl {
             },
             merge: None,
             default: None,
+            cost: None,
+            unextractable: false,
         }
     }
 }
@@ -142,39 +296,72 @@ impl FunctionDecl {
 #[derive(Clone, Debug)]
 pub enum Fact {
     /// Must be at least two things in an eq fact
-    Eq(Vec<Expr>),
+    Eq(Span, Vec<Expr>),
     Fact(Expr),
+    /// Holds when the inner fact doesn't.
+    Not(Span, Box<Fact>),
+}
+
+impl Fact {
+    pub fn span(&self) -> Span {
+        match self {
+            Fact::Eq(span, _) | Fact::Not(span, _) => *span,
+            Fact::Fact(expr) => expr.span(),
+        }
+    }
+
+    /// Calls `f` on each of the fact's expressions, looking through negations.
+    pub fn for_each_expr(&self, f: &mut impl FnMut(&Expr)) {
+        match self {
+            Fact::Eq(_, exprs) => exprs.iter().for_each(f),
+            Fact::Fact(expr) => f(expr),
+            Fact::Not(_, fact) => fact.for_each_expr(f),
+        }
+    }
 }
 
 impl Display for Fact {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Fact::Eq(exprs) => write!(f, "(= {})", ListDisplay(exprs, " ")),
+            Fact::Eq(_, exprs) => write!(f, "(= {})", ListDisplay(exprs, " ")),
             Fact::Fact(e) => Display::fmt(e, f),
+            Fact::Not(_, fact) => write!(f, "(not {fact})"),
         }
     }
 }
 
 #[derive(Clone, Debug)]
 pub enum Action {
-    Define(Symbol, Expr),
-    Set(Symbol, Vec<Expr>, Expr),
-    Union(Expr, Expr),
-    Panic(String),
-    Expr(Expr),
+    Define(Span, Symbol, Expr),
+    Set(Span, Symbol, Vec<Expr>, Expr),
+    Union(Span, Expr, Expr),
+    Panic(Span, String),
+    Expr(Span, Expr),
     // If(Expr, Action, Action),
 }
 
+impl Action {
+    pub fn span(&self) -> Span {
+        match self {
+            Action::Define(span, ..)
+            | Action::Set(span, ..)
+            | Action::Union(span, ..)
+            | Action::Panic(span, ..)
+            | Action::Expr(span, ..) => *span,
+        }
+    }
+}
+
 impl Display for Action {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Action::Define(lhs, rhs) => write!(f, "(define {} {})", lhs, rhs),
-            Action::Set(lhs, args, rhs) => {
+            Action::Define(_, lhs, rhs) => write!(f, "(define {} {})", lhs, rhs),
+            Action::Set(_, lhs, args, rhs) => {
                 write!(f, "(set ({} {}) {})", lhs, ListDisplay(args, ""), rhs)
             }
-            Action::Union(lhs, rhs) => write!(f, "(union {} {})", lhs, rhs),
-            Action::Panic(msg) => write!(f, "(panic {:?})", msg),
-            Action::Expr(e) => Display::fmt(e, f),
+            Action::Union(_, lhs, rhs) => write!(f, "(union {} {})", lhs, rhs),
+            Action::Panic(_, msg) => write!(f, "(panic {:?})", msg),
+            Action::Expr(_, e) => Display::fmt(e, f),
             // Action::If(cond, then, else_) => write!(f, "(if {} {} {})", cond, then, else_),
         }
     }
@@ -182,10 +369,13 @@ impl Display for Action {
 
 #[derive(Clone, Debug)]
 pub struct Rule {
+    pub span: Span,
     // pub query: Query,
     // pub actions: Vec<Action>,
     pub head: Vec<Action>,
     pub body: Vec<Fact>,
+    /// The ruleset this rule belongs to, empty for the default ruleset.
+    pub ruleset: Symbol,
 }
 
 impl Display for Rule {
@@ -201,6 +391,8 @@ impl Display for Rule {
 
 #[derive(Clone, Debug)]
 pub struct Rewrite {
+    pub span: Span,
     pub lhs: Expr,
     pub rhs: Expr,
+    pub ruleset: Symbol,
 }
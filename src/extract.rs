@@ -1,10 +1,30 @@
 use hashbrown::hash_map::Entry;
 
-use crate::ast::{Symbol, Type};
-use crate::util::HashMap;
-use crate::{EGraph, Expr, Id, Value};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+use std::time::Duration;
 
-type Cost = usize;
+use crate::ast::{Fact, FunctionDecl, Span, Symbol, Type};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::util::Stopwatch;
+use crate::util::{HashMap, HashSet};
+use crate::{EGraph, Error, Expr, Id, Value};
+
+pub type Cost = usize;
+
+/// Decides the cost of a node when extracting, from its constructor and the
+/// costs of its arguments, where arguments that aren't e-classes cost 0.
+/// Set one with [`EGraph::set_cost_function`]; otherwise a node costs
+/// the `:cost` of its constructor, 1 by default, plus the costs of its arguments.
+///
+/// A node should cost at least as much as each of its arguments,
+/// and more when its arguments cost more, or the best terms may not be found.
+/// When extracting a DAG, a node costs what it would if its arguments cost 0.
+/// A cost function is `Send` and `Sync` so that an [`EGraph`] holding one can be too.
+pub trait CostFunction: Send + Sync {
+    fn cost(&self, ctor: Symbol, child_costs: &[Cost]) -> Cost;
+}
 
 #[derive(Debug)]
 struct Node {
@@ -12,67 +32,176 @@ struct Node {
     values: Vec<Value>,
 }
 
-struct Extractor<'a> {
+/// Finds the cheapest term in each e-class, made from the extractable
+/// constructors, and any other functions that return an e-class.
+pub(crate) struct Extractor<'a> {
     costs: HashMap<Id, (Cost, Node)>,
     ctors: Vec<Symbol>,
     egraph: &'a EGraph,
 }
 
 impl EGraph {
-    pub fn extract(&mut self, id: Id) -> (Cost, Expr) {
-        Extractor::new(self).find_best(id)
+    /// The cheapest term in the e-class `id`.
+    pub fn extract(&self, id: Id) -> Result<(Cost, Expr), Error> {
+        Extractor::new(self, None).find_best(id)
+    }
+
+    /// The `n` cheapest terms in the e-class `id`, cheapest first, which may
+    /// differ anywhere, not just in their top-level node. An e-class that
+    /// contains itself, like `x` after `(union x (Add x (Num 0)))`, has
+    /// terms of every size, so it always has `n`.
+    pub fn extract_variants(&self, id: Id, n: usize) -> Result<Vec<(Cost, Expr)>, Error> {
+        Extractor::new(self, None).find_variants(id, n)
+    }
+
+    /// The tuples of the functions other than constructors that take the e-class
+    /// `id`, like an analysis of it, with each e-class replaced by its cheapest term.
+    /// A function returning a value is shown as an equality, and a relation
+    /// as a fact. Tuples with an e-class that has no terms are left out.
+    pub fn extract_function_values(&self, id: Id) -> Vec<Fact> {
+        Extractor::new(self, None).function_values(id)
+    }
+
+    /// Sets how the cost of a term is computed when extracting,
+    /// instead of from the `:cost` of each constructor.
+    pub fn set_cost_function(&mut self, cost_function: impl CostFunction + 'static) {
+        self.cost_function = Some(Arc::new(cost_function));
     }
 }
 
 impl<'a> Extractor<'a> {
-    fn new(egraph: &'a EGraph) -> Self {
+    /// Finds the cheapest terms made from the extractable constructors,
+    /// and only those in `only`, if it's given.
+    pub(crate) fn new(egraph: &'a EGraph, only: Option<&[Symbol]>) -> Self {
         let mut extractor = Extractor {
             costs: HashMap::default(),
             egraph,
             ctors: vec![],
         };
 
+        // constructors, and any other functions that return an e-class
         for ctors in egraph.sorts.values() {
             extractor.ctors.extend(ctors.iter().copied())
         }
+        for (name, function) in &egraph.functions {
+            if function.decl.schema.output.is_sort() && !extractor.ctors.contains(name) {
+                extractor.ctors.push(*name);
+            }
+        }
+        extractor.ctors.retain(|ctor| {
+            !egraph.functions[ctor].decl.unextractable
+                && only.is_none_or(|only| only.contains(ctor))
+        });
 
         log::debug!("Extracting from ctors: {:?}", extractor.ctors);
         extractor.find_costs();
         extractor
     }
 
-    fn find_best(&self, id: Id) -> (Cost, Expr) {
+    pub(crate) fn find_variants(&self, id: Id, n: usize) -> Result<Vec<(Cost, Expr)>, Error> {
         let id = self.egraph.find(id);
-        let (cost, node) = &self.costs[&id];
-        let mut children = vec![];
-        for (ty, value) in self.egraph.functions[&node.sym]
-            .decl
-            .schema
-            .input
-            .iter()
-            .zip(&node.values)
-        {
-            if ty.is_sort() {
-                children.push(self.find_best(Id::from(value.clone())).1)
-            } else {
-                children.push(Expr::Lit(value.to_literal()))
+        let mut variants = Variants {
+            extractor: self,
+            candidates: self.candidates(),
+            found: HashMap::default(),
+            queues: HashMap::default(),
+            seen: HashSet::default(),
+            busy: HashSet::default(),
+        };
+        variants.find(id, n.saturating_sub(1));
+        let found = variants.found.get(&id).map_or(0, Vec::len);
+        if found == 0 {
+            return Err(Error::NotExtractable(id));
+        }
+        let mut exprs: Vec<(Cost, Expr)> = (0..found.min(n))
+            .map(|k| (variants.found[&id][k].cost, variants.expr(id, k)))
+            .collect();
+        exprs.sort_by_cached_key(|(cost, expr)| (*cost, expr.to_string()));
+        Ok(exprs)
+    }
+
+    pub(crate) fn function_values(&self, id: Id) -> Vec<Fact> {
+        let egraph = self.egraph;
+        let id = egraph.find(id);
+        let ctors: HashSet<Symbol> = egraph.sorts.values().flatten().copied().collect();
+        let mut names: Vec<Symbol> = (egraph.functions.keys())
+            .filter(|name| !ctors.contains(name))
+            .copied()
+            .collect();
+        names.sort_by_key(|name| name.as_str());
+
+        let mut facts = vec![];
+        for name in names {
+            let schema = &egraph.functions[&name].decl.schema;
+            let mut function_facts = vec![];
+            for (inputs, output) in &egraph.functions[&name].nodes {
+                let takes_id = (schema.input.iter().zip(inputs))
+                    .any(|(ty, v)| ty.is_sort() && egraph.find(Id::from(v.clone())) == id);
+                if !takes_id {
+                    continue;
+                }
+                let args = (schema.input.iter().zip(inputs))
+                    .map(|(ty, v)| self.term(ty, v))
+                    .collect::<Option<Vec<Expr>>>();
+                let Some(args) = args else {
+                    continue;
+                };
+                let call = Expr::call(name, args);
+                if schema.output == Type::Unit {
+                    function_facts.push(Fact::Fact(call));
+                } else if let Some(output) = self.term(&schema.output, &output.value) {
+                    function_facts.push(Fact::Eq(Span::default(), vec![call, output]));
+                }
             }
+            function_facts.sort_by_cached_key(|fact| fact.to_string());
+            facts.extend(function_facts);
         }
+        facts
+    }
 
+    pub(crate) fn find_best(&self, id: Id) -> Result<(Cost, Expr), Error> {
+        let id = self.egraph.find(id);
+        let Some((cost, node)) = self.costs.get(&id) else {
+            return Err(Error::NotExtractable(id));
+        };
+        let types = &self.egraph.functions[&node.sym].decl.schema.input;
+        // a node's children all have costs, or it wouldn't have one
+        let children =
+            (types.iter().zip(&node.values)).map(|(ty, value)| self.term(ty, value).unwrap());
         let expr = Expr::call(node.sym, children);
-        (*cost, expr)
+        Ok((*cost, expr))
     }
 
-    fn node_total_cost(&self, types: &[Type], children: &[Value]) -> Option<Cost> {
-        let mut cost = 1;
-        for (ty, value) in types.iter().zip(children) {
-            cost += if ty.is_sort() {
-                self.costs.get(&Id::from(value.clone()))?.0
-            } else {
-                0
-            }
+    /// The cheapest term for a value of type `ty`, which is just the value if
+    /// it isn't an e-class, or None for an e-class without any terms.
+    fn term(&self, ty: &Type, value: &Value) -> Option<Expr> {
+        if ty.is_sort() {
+            let id = self.egraph.find(Id::from(value.clone()));
+            self.find_best(id).ok().map(|(_, expr)| expr)
+        } else {
+            Some(Expr::lit(value.to_literal()))
+        }
+    }
+
+    fn node_total_cost(&self, decl: &FunctionDecl, children: &[Value]) -> Option<Cost> {
+        let child_costs = (decl.schema.input.iter().zip(children))
+            .map(|(ty, value)| {
+                if ty.is_sort() {
+                    Some(self.costs.get(&Id::from(value.clone()))?.0)
+                } else {
+                    Some(0)
+                }
+            })
+            .collect::<Option<Vec<Cost>>>()?;
+        Some(self.cost_with(decl, child_costs))
+    }
+
+    /// The cost of a node whose arguments cost `child_costs`.
+    fn cost_with(&self, decl: &FunctionDecl, child_costs: Vec<Cost>) -> Cost {
+        match &self.egraph.cost_function {
+            Some(cost_function) => cost_function.cost(decl.name, &child_costs),
+            None => (child_costs.into_iter()).fold(decl.cost.unwrap_or(1), Cost::saturating_add),
         }
-        Some(cost)
     }
 
     fn find_costs(&mut self) {
@@ -83,8 +212,8 @@ impl<'a> Extractor<'a> {
             for &sym in &self.ctors {
                 let func = &self.egraph.functions[&sym];
                 assert!(func.decl.schema.output.is_sort());
-                for (inputs, output) in &func.nodes {
-                    if let Some(new_cost) = self.node_total_cost(&func.decl.schema.input, inputs) {
+                for (inputs, output) in func.nodes.iter().map(|(k, out)| (k, &out.value)) {
+                    if let Some(new_cost) = self.node_total_cost(&func.decl, inputs) {
                         let make_new_pair = || {
                             let values = inputs.clone();
                             (new_cost, Node { sym, values })
@@ -107,3 +236,644 @@ impl<'a> Extractor<'a> {
         }
     }
 }
+
+/// A term of an e-class, as the index of one of its nodes, and the index of
+/// a variant of each of the node's children, which are e-classes.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Variant {
+    cost: Cost,
+    node: usize,
+    children: Vec<usize>,
+}
+
+/// Finds the cheapest terms of each e-class one at a time, only as they're
+/// needed. The next cheapest term of an e-class is either made from one of
+/// its nodes and the cheapest terms of its children, or a term found so far
+/// with one of its children replaced by that child's next cheapest term.
+struct Variants<'a, 'b> {
+    extractor: &'b Extractor<'a>,
+    candidates: HashMap<Id, Vec<Candidate>>,
+    /// The variants of each e-class found so far, cheapest first.
+    found: HashMap<Id, Vec<Variant>>,
+    /// The variants of each e-class that might be the next cheapest.
+    queues: HashMap<Id, BinaryHeap<Reverse<Variant>>>,
+    seen: HashSet<(Id, usize, Vec<usize>)>,
+    /// The e-classes whose next variant is being found, which can't
+    /// be used to find it, in case costs don't always increase.
+    busy: HashSet<Id>,
+}
+
+impl<'a, 'b> Variants<'a, 'b> {
+    /// Finds the variants of the e-class `id` up to the `k`th,
+    /// counting from 0, returning whether it has that many.
+    fn find(&mut self, id: Id, k: usize) -> bool {
+        if !self.found.contains_key(&id) {
+            // start from each node with the cheapest terms of its children
+            let Some(candidates) = self.candidates.get(&id) else {
+                return false;
+            };
+            let mut queue = BinaryHeap::new();
+            for (node, candidate) in candidates.iter().enumerate() {
+                let children = vec![0; candidate.children.len()];
+                if let Some(cost) = self.cost(id, node, &children) {
+                    self.seen.insert((id, node, children.clone()));
+                    queue.push(Reverse(Variant {
+                        cost,
+                        node,
+                        children,
+                    }));
+                }
+            }
+            self.found.insert(id, vec![]);
+            self.queues.insert(id, queue);
+        }
+        while self.found[&id].len() <= k {
+            if !self.busy.insert(id) {
+                return false;
+            }
+            // the last variant found with each child's next variant
+            if let Some(last) = self.found[&id].last().cloned() {
+                let node_children = self.candidates[&id][last.node].children.clone();
+                for (i, child) in node_children.into_iter().enumerate() {
+                    let mut children = last.children.clone();
+                    children[i] += 1;
+                    if self.seen.contains(&(id, last.node, children.clone()))
+                        || !self.find(child, children[i])
+                    {
+                        continue;
+                    }
+                    if let Some(cost) = self.cost(id, last.node, &children) {
+                        self.seen.insert((id, last.node, children.clone()));
+                        let variant = Variant {
+                            cost,
+                            node: last.node,
+                            children,
+                        };
+                        self.queues.get_mut(&id).unwrap().push(Reverse(variant));
+                    }
+                }
+            }
+            self.busy.remove(&id);
+            let Some(Reverse(variant)) = self.queues.get_mut(&id).unwrap().pop() else {
+                return false;
+            };
+            self.found.get_mut(&id).unwrap().push(variant);
+        }
+        true
+    }
+
+    /// The cost of a node of `id` made from the given variants of its children,
+    /// where the first variant of each costs what its cheapest term does.
+    fn cost(&self, id: Id, node: usize, children: &[usize]) -> Option<Cost> {
+        let candidate = &self.candidates[&id][node];
+        let decl = &self.extractor.egraph.functions[&candidate.sym].decl;
+        let mut child_variants = candidate.children.iter().zip(children);
+        let child_costs = (decl.schema.input.iter())
+            .map(|ty| {
+                if !ty.is_sort() {
+                    return Some(0);
+                }
+                match child_variants.next()? {
+                    (child, 0) => Some(self.extractor.costs.get(child)?.0),
+                    (child, &k) => Some(self.found.get(child)?.get(k)?.cost),
+                }
+            })
+            .collect::<Option<Vec<Cost>>>()?;
+        Some(self.extractor.cost_with(decl, child_costs))
+    }
+
+    /// The `k`th variant of the e-class `id`, which must have been found.
+    fn expr(&mut self, id: Id, k: usize) -> Expr {
+        let variant = self.found[&id][k].clone();
+        let candidate = &self.candidates[&id][variant.node];
+        let (sym, values) = (candidate.sym, candidate.values.clone());
+        let types = &self.extractor.egraph.functions[&sym].decl.schema.input;
+        let mut child_variants = candidate.children.clone().into_iter().zip(variant.children);
+        let mut children = vec![];
+        for (ty, value) in types.iter().zip(&values) {
+            if ty.is_sort() {
+                let (child, k) = child_variants.next().unwrap();
+                // the cheapest term of a child might not have been needed yet
+                self.find(child, k);
+                children.push(self.expr(child, k));
+            } else {
+                children.push(Expr::lit(value.to_literal()));
+            }
+        }
+        Expr::call(sym, children)
+    }
+}
+
+/// How long to search for the cheapest DAG, if the `extract` command doesn't say.
+pub(crate) const DEFAULT_DAG_TIME_LIMIT: Duration = Duration::from_secs(1);
+
+/// A node of an e-class that can be extracted.
+struct Candidate {
+    sym: Symbol,
+    values: Vec<Value>,
+    /// The cost of the node itself, not counting its children.
+    cost: Cost,
+    /// The e-classes of its children.
+    children: Vec<Id>,
+}
+
+/// The node chosen for each e-class of a DAG, as an index into its candidates.
+type Choices = HashMap<Id, usize>;
+
+/// Chooses a node for each e-class reachable from a root, minimizing the
+/// total cost of the chosen nodes, so a shared subterm is only paid for once.
+struct DagExtractor<'a> {
+    egraph: &'a EGraph,
+    /// The nodes of each e-class that have terms, cheapest first.
+    candidates: HashMap<Id, Vec<Candidate>>,
+}
+
+impl EGraph {
+    /// The cheapest term in the e-class `id` when each shared subterm is only
+    /// paid for once, written with a `let` for every e-class used more than once,
+    /// like `(let v0 (Var "x") (Add v0 v0))`. The search for the cheapest gives up
+    /// after `time_limit`, returning the cheapest found by then, which is at
+    /// least as cheap as a greedy choice of nodes. On wasm, which has no clock,
+    /// the time limit is approximated by a number of steps of the search.
+    /// A DAG of more than 512 e-classes isn't searched at all, and gets the
+    /// greedy choice.
+    pub fn extract_dag(&self, id: Id, time_limit: Duration) -> Result<(Cost, Expr), Error> {
+        Extractor::new(self, None).find_best_dag(id, time_limit)
+    }
+}
+
+impl<'a> Extractor<'a> {
+    pub(crate) fn find_best_dag(
+        &self,
+        id: Id,
+        time_limit: Duration,
+    ) -> Result<(Cost, Expr), Error> {
+        let dag = DagExtractor::new(self);
+        let root = self.egraph.find(id);
+
+        // start from the better of the greedy choices and those of the best tree,
+        // either of which might be cyclic if the cost function is unusual
+        let (mut best_cost, mut best) = [dag.greedy_choices(), dag.tree_choices(self)]
+            .into_iter()
+            .filter_map(|choices| Some((dag.dag_cost(&choices, root)?, choices)))
+            .min_by_key(|(cost, _)| *cost)
+            .ok_or(Error::NotExtractable(root))?;
+
+        match Search::new(&dag, root, time_limit, best_cost) {
+            Some(mut search) => {
+                search.search();
+                if search.timed_out {
+                    log::info!("Gave up searching for the cheapest DAG after {time_limit:?}");
+                }
+                if let Some(choices) = search.best_choices() {
+                    (best_cost, best) = (search.best_cost, choices);
+                }
+            }
+            None => log::info!(
+                "Not searching for the cheapest DAG of more than {MAX_SEARCH_CLASSES} e-classes"
+            ),
+        }
+        Ok((best_cost, dag.to_expr(&best, root)))
+    }
+
+    /// The cost of a node without its children, which is what it costs when
+    /// its children cost nothing.
+    fn node_cost(&self, decl: &FunctionDecl) -> Cost {
+        self.cost_with(decl, vec![0; decl.schema.input.len()])
+    }
+
+    /// The nodes of each e-class that have terms, cheapest first
+    /// when their children cost nothing.
+    fn candidates(&self) -> HashMap<Id, Vec<Candidate>> {
+        let egraph = self.egraph;
+        let mut candidates = HashMap::<Id, Vec<Candidate>>::default();
+        for &sym in &self.ctors {
+            let function = &egraph.functions[&sym];
+            let input = &function.decl.schema.input;
+            let cost = self.node_cost(&function.decl);
+            for (values, output) in &function.nodes {
+                let children: Vec<Id> = (input.iter().zip(values))
+                    .filter(|(ty, _)| ty.is_sort())
+                    .map(|(_, value)| egraph.find(Id::from(value.clone())))
+                    .collect();
+                // a node with a child that has no terms has none either
+                if children.iter().all(|c| self.costs.contains_key(c)) {
+                    let id = egraph.find(Id::from(output.value.clone()));
+                    candidates.entry(id).or_default().push(Candidate {
+                        sym,
+                        values: values.clone(),
+                        cost,
+                        children,
+                    });
+                }
+            }
+        }
+        // in the same order every time, so ties are broken the same way
+        for nodes in candidates.values_mut() {
+            nodes.sort_by(|a, b| {
+                let key = |n: &Candidate| (n.cost, n.sym.as_str(), n.values.clone());
+                key(a).cmp(&key(b))
+            });
+        }
+        candidates
+    }
+}
+
+impl<'a> DagExtractor<'a> {
+    fn new(extractor: &Extractor<'a>) -> Self {
+        DagExtractor {
+            egraph: extractor.egraph,
+            candidates: extractor.candidates(),
+        }
+    }
+
+    /// The nodes of the cheapest trees.
+    fn tree_choices(&self, extractor: &Extractor) -> Choices {
+        (self.candidates.iter())
+            .filter_map(|(id, nodes)| {
+                let (_, best) = extractor.costs.get(id)?;
+                let i = nodes
+                    .iter()
+                    .position(|n| n.sym == best.sym && n.values == best.values)?;
+                Some((*id, i))
+            })
+            .collect()
+    }
+
+    /// Chooses the node for each e-class with the cheapest DAG,
+    /// given the DAGs chosen for its children so far.
+    fn greedy_choices(&self) -> Choices {
+        // the choice for each e-class, with the cost of its DAG
+        // and the e-classes in it, each with the cost of its node
+        let mut dags = HashMap::<Id, (usize, Cost, HashMap<Id, Cost>)>::default();
+        let mut parents = HashMap::<Id, Vec<Id>>::default();
+        for (&id, nodes) in &self.candidates {
+            for child in nodes.iter().flat_map(|node| &node.children) {
+                parents.entry(*child).or_default().push(id);
+            }
+        }
+        // the e-classes whose children's DAGs might have gotten cheaper
+        let mut todo: Vec<Id> = self.candidates.keys().copied().collect();
+        let mut in_todo: HashSet<Id> = todo.iter().copied().collect();
+        while let Some(id) = todo.pop() {
+            in_todo.remove(&id);
+            let mut changed = false;
+            for (i, node) in self.candidates[&id].iter().enumerate() {
+                let mut dag = HashMap::<Id, Cost>::default();
+                for child in &node.children {
+                    match dags.get(child) {
+                        Some((_, _, child_dag)) if !child_dag.contains_key(&id) => {
+                            dag.extend(child_dag)
+                        }
+                        // not extracted yet, or would be a cycle
+                        _ => break,
+                    }
+                }
+                if !node.children.iter().all(|c| dag.contains_key(c)) {
+                    continue;
+                }
+                dag.insert(id, node.cost);
+                let cost = dag.values().fold(0, |a: Cost, b| a.saturating_add(*b));
+                if dags.get(&id).is_none_or(|(_, old, _)| cost < *old) {
+                    dags.insert(id, (i, cost, dag));
+                    changed = true;
+                }
+            }
+            if changed {
+                for &parent in parents.get(&id).into_iter().flatten() {
+                    if in_todo.insert(parent) {
+                        todo.push(parent);
+                    }
+                }
+            }
+        }
+        dags.into_iter().map(|(id, (i, ..))| (id, i)).collect()
+    }
+
+    /// The total cost of the nodes chosen for the e-classes reachable from
+    /// `root`, or None if they don't make a DAG.
+    fn dag_cost(&self, choices: &Choices, root: Id) -> Option<Cost> {
+        // the e-classes being visited, each with how many of its children have been
+        let mut stack = vec![(root, 0)];
+        let mut visiting = HashSet::from_iter([root]);
+        let mut done = HashSet::default();
+        let mut cost: Cost = 0;
+        while let Some((id, n)) = stack.last_mut() {
+            let id = *id;
+            let node = &self.candidates.get(&id)?[*choices.get(&id)?];
+            match node.children.get(*n) {
+                Some(&child) => {
+                    *n += 1;
+                    if !done.contains(&child) {
+                        if !visiting.insert(child) {
+                            return None;
+                        }
+                        stack.push((child, 0));
+                    }
+                }
+                None => {
+                    cost = cost.saturating_add(node.cost);
+                    visiting.remove(&id);
+                    done.insert(id);
+                    stack.pop();
+                }
+            }
+        }
+        Some(cost)
+    }
+
+    /// Writes the chosen DAG as a term, binding each e-class used more than
+    /// once with a `let`, which isn't a function, but is written like a call.
+    fn to_expr(&self, choices: &Choices, root: Id) -> Expr {
+        // count the uses of each e-class, and order them children first
+        let mut uses = HashMap::<Id, usize>::default();
+        let mut order = vec![];
+        let mut todo = vec![(root, false)];
+        while let Some((id, expanded)) = todo.pop() {
+            if expanded {
+                order.push(id);
+                continue;
+            }
+            let n = uses.entry(id).or_default();
+            *n += 1;
+            if *n == 1 {
+                todo.push((id, true));
+                let children = &self.candidates[&id][choices[&id]].children;
+                todo.extend(children.iter().rev().map(|&child| (child, false)));
+            }
+        }
+
+        let mut exprs = HashMap::<Id, Expr>::default();
+        let mut bindings = vec![];
+        for id in order {
+            let node = &self.candidates[&id][choices[&id]];
+            let types = &self.egraph.functions[&node.sym].decl.schema.input;
+            let children = (types.iter().zip(&node.values)).map(|(ty, value)| {
+                if !ty.is_sort() {
+                    Expr::lit(value.to_literal())
+                } else {
+                    let child = self.egraph.find(Id::from(value.clone()));
+                    if uses[&child] == 1 {
+                        exprs.remove(&child).unwrap()
+                    } else {
+                        exprs[&child].clone()
+                    }
+                }
+            });
+            let expr = Expr::call(node.sym, children);
+            if uses[&id] > 1 {
+                let var = Symbol::from(format!("v{}", bindings.len()));
+                bindings.push((var, expr));
+                exprs.insert(id, Expr::var(var));
+            } else {
+                exprs.insert(id, expr);
+            }
+        }
+        let body = exprs.remove(&root).unwrap();
+        (bindings.into_iter().rev()).fold(body, |body, (var, expr)| {
+            Expr::call("let", [Expr::var(var), expr, body])
+        })
+    }
+}
+
+/// The most e-classes [`Search`] looks for the cheapest DAG of; for a larger
+/// DAG extraction settles for the greedy choices.
+const MAX_SEARCH_CLASSES: usize = 512;
+
+/// How many steps [`Search`] takes per millisecond of its time limit on wasm,
+/// where there is no clock to time it with. Searches natively take a few
+/// thousand steps per millisecond, so this errs on the side of stopping early.
+#[cfg(target_arch = "wasm32")]
+const SEARCH_STEPS_PER_MILLI: u128 = 1000;
+
+/// A set of the e-classes of a [`Search`], by their index.
+#[derive(Clone)]
+struct ClassSet(Vec<u64>);
+
+impl ClassSet {
+    fn new(n: usize) -> Self {
+        ClassSet(vec![0; n.div_ceil(64)])
+    }
+
+    fn contains(&self, i: usize) -> bool {
+        self.0[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    /// Adds `i`, returning whether it wasn't in the set.
+    fn insert(&mut self, i: usize) -> bool {
+        let new = !self.contains(i);
+        self.0[i / 64] |= 1 << (i % 64);
+        new
+    }
+
+    fn remove(&mut self, i: usize) {
+        self.0[i / 64] &= !(1 << (i % 64));
+    }
+
+    fn union_with(&mut self, other: &ClassSet) {
+        for (a, b) in self.0.iter_mut().zip(&other.0) {
+            *a |= b;
+        }
+    }
+
+    fn first(&self) -> Option<usize> {
+        let (i, word) = self.0.iter().enumerate().find(|(_, w)| **w != 0)?;
+        Some(i * 64 + word.trailing_zeros() as usize)
+    }
+}
+
+/// The choice of a node for one e-class in a [`Search`], with the state
+/// to go back to before trying its next node.
+struct Frame {
+    class: usize,
+    /// The index of the next node to try.
+    next: usize,
+    /// The cost of the nodes chosen so far.
+    cost: Cost,
+    /// A lower bound on the cost of any DAG with the nodes chosen so far.
+    bound: Cost,
+    /// The e-classes that must be chosen for, including `class`.
+    pending: ClassSet,
+    /// The length of the search's trail.
+    trail: usize,
+}
+
+/// A branch and bound search for the cheapest DAG, which chooses a node for
+/// one e-class at a time, and gives up on choices that can't beat the best so far.
+struct Search<'a> {
+    /// The e-classes reachable from the root, which is the first.
+    ids: Vec<Id>,
+    nodes: Vec<&'a [Candidate]>,
+    /// The indices of the children of each node.
+    children: Vec<Vec<Vec<usize>>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    stopwatch: Stopwatch,
+    time_limit: Duration,
+    /// The number of frames the search has visited.
+    steps: u128,
+    best_cost: Cost,
+    /// The cheapest choices found by the search, if they beat the initial cost.
+    best: Option<Vec<Option<usize>>>,
+    timed_out: bool,
+}
+
+impl<'a> Search<'a> {
+    /// A search for a DAG cheaper than `best_cost`,
+    /// or None if the DAG has too many e-classes to search.
+    fn new(
+        dag: &'a DagExtractor<'a>,
+        root: Id,
+        time_limit: Duration,
+        best_cost: Cost,
+    ) -> Option<Self> {
+        let mut index = HashMap::from_iter([(root, 0)]);
+        let mut ids = vec![root];
+        let mut i = 0;
+        while let Some(id) = ids.get(i) {
+            for node in &dag.candidates[id] {
+                for &child in &node.children {
+                    if let Entry::Vacant(entry) = index.entry(child) {
+                        entry.insert(ids.len());
+                        ids.push(child);
+                    }
+                }
+            }
+            if ids.len() > MAX_SEARCH_CLASSES {
+                return None;
+            }
+            i += 1;
+        }
+        let nodes: Vec<_> = ids.iter().map(|id| &dag.candidates[id][..]).collect();
+        let children = (nodes.iter())
+            .map(|nodes| {
+                (nodes.iter())
+                    .map(|node| node.children.iter().map(|c| index[c]).collect())
+                    .collect()
+            })
+            .collect();
+        Some(Search {
+            ids,
+            nodes,
+            children,
+            #[cfg(not(target_arch = "wasm32"))]
+            stopwatch: Stopwatch::start(),
+            time_limit,
+            steps: 0,
+            best_cost,
+            best: None,
+            timed_out: false,
+        })
+    }
+
+    /// Counts a step of the search and says whether it has run out of time,
+    /// which on wasm is measured in steps.
+    fn out_of_time(&mut self) -> bool {
+        self.steps += 1;
+        #[cfg(not(target_arch = "wasm32"))]
+        return self.stopwatch.elapsed() > self.time_limit;
+        #[cfg(target_arch = "wasm32")]
+        return self.steps > self.time_limit.as_millis() * SEARCH_STEPS_PER_MILLI;
+    }
+
+    /// The cost of the cheapest node of an e-class.
+    fn min_cost(&self, class: usize) -> Cost {
+        self.nodes[class][0].cost
+    }
+
+    /// Tries every choice of nodes that might beat the best so far, keeping
+    /// track of the e-classes each one leads to through the chosen nodes,
+    /// so that choices making a cycle are skipped.
+    fn search(&mut self) {
+        let n = self.ids.len();
+        let mut chosen = vec![None; n];
+        let mut reach: Vec<ClassSet> = (0..n)
+            .map(|i| {
+                let mut set = ClassSet::new(n);
+                set.insert(i);
+                set
+            })
+            .collect();
+        // the sets in `reach` before they were extended, to undo choices
+        let mut trail = vec![];
+        let mut pending = ClassSet::new(n);
+        pending.insert(0);
+        let mut frames = vec![Frame {
+            class: 0,
+            next: 0,
+            cost: 0,
+            bound: self.min_cost(0),
+            pending,
+            trail: 0,
+        }];
+        while let Some(frame) = frames.last_mut() {
+            if self.out_of_time() {
+                self.timed_out = true;
+                return;
+            }
+            // undo the last node tried for this e-class
+            let class = frame.class;
+            chosen[class] = None;
+            for (i, set) in trail.drain(frame.trail..).rev() {
+                reach[i] = set;
+            }
+            let i = frame.next;
+            let Some(node) = self.nodes[class].get(i) else {
+                frames.pop();
+                continue;
+            };
+            frame.next += 1;
+            let children = &self.children[class][i];
+            if children.iter().any(|&child| reach[child].contains(class)) {
+                continue;
+            }
+
+            // every pending e-class costs at least its cheapest node
+            let cost = frame.cost.saturating_add(node.cost);
+            let mut bound = (frame.bound - self.min_cost(class)).saturating_add(node.cost);
+            let mut pending = frame.pending.clone();
+            pending.remove(class);
+            for &child in children {
+                if chosen[child].is_none() && pending.insert(child) {
+                    bound = bound.saturating_add(self.min_cost(child));
+                }
+            }
+            if bound >= self.best_cost {
+                continue;
+            }
+
+            chosen[class] = Some(i);
+            let mut leads_to = ClassSet::new(n);
+            for &child in children {
+                leads_to.union_with(&reach[child]);
+            }
+            for (j, set) in reach.iter_mut().enumerate() {
+                if set.contains(class) {
+                    trail.push((j, set.clone()));
+                    set.union_with(&leads_to);
+                }
+            }
+            match pending.first() {
+                Some(class) => frames.push(Frame {
+                    class,
+                    next: 0,
+                    cost,
+                    bound,
+                    pending,
+                    trail: trail.len(),
+                }),
+                None => {
+                    self.best_cost = cost;
+                    self.best = Some(chosen.clone());
+                }
+            }
+        }
+    }
+
+    fn best_choices(&self) -> Option<Choices> {
+        let best = self.best.as_ref()?;
+        let choices = (self.ids.iter().zip(best))
+            .filter_map(|(id, choice)| Some((*id, (*choice)?)))
+            .collect();
+        Some(choices)
+    }
+}
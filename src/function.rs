@@ -0,0 +1,264 @@
+use crate::*;
+
+#[derive(Clone)]
+pub struct Function {
+    pub(crate) decl: FunctionDecl,
+    pub(crate) nodes: HashMap<Vec<Value>, TupleOutput>,
+    /// Tries over the tuples (inputs followed by the output),
+    /// keyed by the order in which they store the columns.
+    indexes: HashMap<Vec<usize>, Trie>,
+    /// For each id, the tuples that mention it (as an input or the output),
+    /// so rebuilding can find them once the id is no longer canonical.
+    /// Entries aren't removed along with their tuples, so some are stale.
+    uses: HashMap<Value, Vec<Vec<Value>>>,
+    /// The total length of the use lists.
+    n_uses: usize,
+    /// Tuples inserted since the last rebuild. They may mention ids that had
+    /// already stopped being canonical, so rebuilding checks them too.
+    pending: Vec<Vec<Value>>,
+    /// Tuples whose arguments became equal during rebuilding to those of a tuple
+    /// in the table, but whose (non-id) outputs differ, as (arguments, output).
+    /// The tuple in the table stays there until the egraph merges these into it.
+    pub(crate) conflicts: Vec<(Vec<Value>, Value)>,
+    pub(crate) updates: usize,
+}
+
+/// The output of a tuple, along with the timestamp at which it last changed.
+#[derive(Clone, Debug)]
+pub(crate) struct TupleOutput {
+    pub value: Value,
+    pub timestamp: u32,
+    /// With proofs enabled, the id of a term that calls this function on
+    /// arguments equal to this tuple's, for justifying congruence.
+    pub term: Option<Id>,
+}
+
+impl Function {
+    pub fn new(decl: FunctionDecl) -> Self {
+        Self {
+            decl,
+            nodes: Default::default(),
+            indexes: Default::default(),
+            uses: Default::default(),
+            n_uses: 0,
+            pending: Default::default(),
+            conflicts: Default::default(),
+            updates: 0,
+        }
+    }
+
+    pub fn get(&self, args: &[Value]) -> Option<&Value> {
+        self.nodes.get(args).map(|out| &out.value)
+    }
+
+    /// Inserts a tuple, returning the old output if there was one.
+    /// Counts an update and stamps the tuple with `timestamp`
+    /// unless the tuple was already present with the same output.
+    pub fn insert(&mut self, args: Vec<Value>, value: Value, timestamp: u32) -> Option<Value> {
+        if let Some(old) = self.nodes.get(&args) {
+            if old.value == value {
+                return Some(value);
+            }
+        }
+        self.updates += 1;
+        self.pending.push(args.clone());
+        let old = self.remove(&args);
+        let term = old.as_ref().and_then(|out| out.term);
+        self.insert_new(
+            args,
+            TupleOutput {
+                value,
+                timestamp,
+                term,
+            },
+        );
+        old.map(|out| out.value)
+    }
+
+    /// Inserts a new tuple whose output is the id of the term that made it.
+    pub(crate) fn insert_term(&mut self, args: Vec<Value>, term: Id, timestamp: u32) {
+        self.updates += 1;
+        self.pending.push(args.clone());
+        let out = TupleOutput {
+            value: term.into(),
+            timestamp,
+            term: Some(term),
+        };
+        self.insert_new(args, out);
+    }
+
+    /// The id of the term behind a tuple, or its output if there isn't one.
+    pub(crate) fn get_term(&self, args: &[Value]) -> Option<Value> {
+        let out = self.nodes.get(args)?;
+        Some(out.term.map_or_else(|| out.value.clone(), Value::from))
+    }
+
+    /// Inserts a tuple that isn't already present, updating the indexes and use lists.
+    fn insert_new(&mut self, mut args: Vec<Value>, out: TupleOutput) {
+        args.push(out.value.clone());
+        for (order, trie) in &mut self.indexes {
+            trie.insert(order, &args, out.timestamp);
+        }
+        args.pop();
+        for value in sort_values(&self.decl.schema, &args, &out.value) {
+            self.uses
+                .entry(value.clone())
+                .or_default()
+                .push(args.clone());
+            self.n_uses += 1;
+        }
+        let old = self.nodes.insert(args, out);
+        debug_assert!(old.is_none());
+    }
+
+    /// Removes a tuple from the table and the indexes.
+    fn remove(&mut self, args: &[Value]) -> Option<TupleOutput> {
+        let out = self.nodes.remove(args)?;
+        if !self.indexes.is_empty() {
+            let mut tuple = args.to_vec();
+            tuple.push(out.value.clone());
+            for (order, trie) in &mut self.indexes {
+                trie.remove(order, &tuple);
+            }
+        }
+        Some(out)
+    }
+
+    /// Makes sure there is an index with the columns in the given order.
+    pub(crate) fn build_index(&mut self, order: &[usize]) {
+        if self.indexes.contains_key(order) {
+            return;
+        }
+        let mut trie = Trie::default();
+        let mut tuple = vec![];
+        for (args, out) in &self.nodes {
+            tuple.clear();
+            tuple.extend(args.iter().cloned());
+            tuple.push(out.value.clone());
+            trie.insert(order, &tuple, out.timestamp);
+        }
+        self.indexes.insert(order.to_vec(), trie);
+    }
+
+    /// Gets an index previously made by [`Function::build_index`].
+    pub(crate) fn index(&self, order: &[usize]) -> &Trie {
+        &self.indexes[order]
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The number of distinct values in each column (the inputs followed by the output).
+    pub(crate) fn column_sizes(&self) -> Vec<usize> {
+        let mut columns = vec![HashSet::<&Value>::default(); self.decl.schema.input.len() + 1];
+        for (args, out) in &self.nodes {
+            for (column, value) in columns.iter_mut().zip(args.iter().chain([&out.value])) {
+                column.insert(value);
+            }
+        }
+        columns.iter().map(|c| c.len()).collect()
+    }
+
+    /// Canonicalizes the tuples that mention the `dirty` ids (those that
+    /// stopped being canonical since the last call) or were inserted since
+    /// the last call, returning the number of unions and table updates
+    /// made since the last rebuild.
+    /// Tuples that change are stamped with `timestamp`.
+    pub fn rebuild(&mut self, uf: &mut UnionFind, dirty: &[Id], timestamp: u32) -> usize {
+        let n_unions = uf.n_unions();
+
+        let mut to_check = std::mem::take(&mut self.pending);
+        for id in dirty {
+            // the id will never be canonical again, so neither will its uses
+            if let Some(uses) = self.uses.remove(&Value::from(*id)) {
+                self.n_uses -= uses.len();
+                to_check.extend(uses);
+            }
+        }
+
+        let is_canonical = |uf: &mut UnionFind, ty: &Type, value: &Value| {
+            !ty.is_sort() || &uf.find_mut_value(value.clone()) == value
+        };
+        // this may see a tuple more than once, but it will be canonical by then
+        for mut args in to_check {
+            let Some(out) = self.nodes.get(&args) else {
+                continue;
+            };
+            let canonical = (args.iter().zip(&self.decl.schema.input))
+                .all(|(a, ty)| is_canonical(uf, ty, a))
+                && is_canonical(uf, &self.decl.schema.output, &out.value);
+            if canonical {
+                continue;
+            }
+
+            let mut out = self.remove(&args).unwrap();
+            for (a, ty) in args.iter_mut().zip(&self.decl.schema.input) {
+                if ty.is_sort() {
+                    *a = uf.find_mut_value(a.clone());
+                }
+            }
+            if self.decl.schema.output.is_sort() {
+                out.value = uf.find_mut_value(out.value);
+            }
+            out.timestamp = timestamp;
+
+            if let Some(out2) = self.remove(&args) {
+                if self.decl.schema.output.is_sort() {
+                    out.value = match (out.term, out2.term) {
+                        (Some(a), Some(b)) => {
+                            uf.union_values(a.into(), b.into(), || Justification::Congruence)
+                        }
+                        // congruence is between terms, which tuples set for a
+                        // function with a merge expression don't have
+                        _ => uf.union_unexplained(out.value, out2.value),
+                    };
+                } else if out.value != out2.value {
+                    self.conflicts.push((args.clone(), out.value));
+                    self.insert_new(args, out2);
+                    continue;
+                } else {
+                    out = out2;
+                }
+            }
+            self.insert_new(args, out);
+        }
+
+        // throw out the stale uses once they outnumber the live ones
+        let schema = &self.decl.schema;
+        let n_sort_columns = (schema.input.iter().chain([&schema.output]))
+            .filter(|ty| ty.is_sort())
+            .count();
+        if self.n_uses > 2 * n_sort_columns * self.nodes.len() + 1024 {
+            self.uses.clear();
+            self.n_uses = 0;
+            for (args, out) in &self.nodes {
+                for value in sort_values(&self.decl.schema, args, &out.value) {
+                    self.uses
+                        .entry(value.clone())
+                        .or_default()
+                        .push(args.clone());
+                    self.n_uses += 1;
+                }
+            }
+        }
+
+        uf.n_unions() - n_unions + std::mem::take(&mut self.updates)
+    }
+}
+
+/// The values of a tuple that are ids.
+fn sort_values<'a>(
+    schema: &'a Schema,
+    args: &'a [Value],
+    output: &'a Value,
+) -> impl Iterator<Item = &'a Value> {
+    let types = schema.input.iter().chain([&schema.output]);
+    (args.iter().chain([output]))
+        .zip(types)
+        .filter_map(|(value, ty)| ty.is_sort().then_some(value))
+}
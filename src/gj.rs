@@ -1,5 +1,6 @@
 use crate::*;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display};
+use std::ops::Range;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Atom(pub Symbol, pub Vec<AtomTerm>);
@@ -13,35 +14,109 @@ impl Atom {
     }
 }
 
+impl Display for Atom {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({} {})", self.0, ListDisplay(&self.1, " "))
+    }
+}
+
+/// A call to a primitive in a query, whose last term is the output.
+#[derive(Debug, Clone)]
+pub struct PrimAtom(pub Symbol, pub Primitive, pub Vec<AtomTerm>);
+
+impl PrimAtom {
+    fn inputs(&self) -> &[AtomTerm] {
+        &self.2[..self.2.len() - 1]
+    }
+
+    fn output(&self) -> &AtomTerm {
+        self.2.last().unwrap()
+    }
+
+    fn vars(&self) -> impl Iterator<Item = IndexVar> + '_ {
+        self.2.iter().filter_map(|t| match t {
+            AtomTerm::Var(v) => Some(*v),
+            AtomTerm::Value(_) => None,
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum AtomTerm {
     Var(IndexVar),
     Value(Value),
 }
 
-enum Constraint {
-    Eq(usize, usize),
-    Const(usize, Value),
+impl Display for AtomTerm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AtomTerm::Var(v) => write!(f, "?{v}"),
+            AtomTerm::Value(val) => Display::fmt(val, f),
+        }
+    }
 }
 
 pub type IndexVar = usize;
 
-#[derive(Debug, Clone, Default)]
-struct Trie(HashMap<Value, Self>);
+/// A trie over the columns of a function's tuples, stored in some order.
+/// Every node knows the range of timestamps of the tuples beneath it,
+/// though this may be an overapproximation once tuples are removed.
+#[derive(Debug, Clone)]
+pub(crate) struct Trie {
+    children: HashMap<Value, Self>,
+    min_timestamp: u32,
+    max_timestamp: u32,
+}
 
-impl Trie {
-    fn len(&self) -> usize {
-        self.0.len()
+impl Default for Trie {
+    fn default() -> Self {
+        Self {
+            children: Default::default(),
+            min_timestamp: u32::MAX,
+            max_timestamp: 0,
+        }
     }
 }
 
 impl Trie {
-    fn insert(&mut self, shuffle: &[usize], tuple: &[Value]) {
-        // debug_assert_eq!(shuffle.len(), tuple.len());
-        debug_assert!(shuffle.len() <= tuple.len());
+    fn len(&self) -> usize {
+        self.children.len()
+    }
+
+    fn may_contain(&self, timestamps: &Range<u32>) -> bool {
+        self.min_timestamp < timestamps.end && timestamps.start <= self.max_timestamp
+    }
+
+    fn get(&self, value: &Value, timestamps: &Range<u32>) -> Option<&Self> {
+        self.children
+            .get(value)
+            .filter(|trie| trie.may_contain(timestamps))
+    }
+
+    fn stamp(&mut self, timestamp: u32) {
+        self.min_timestamp = self.min_timestamp.min(timestamp);
+        self.max_timestamp = self.max_timestamp.max(timestamp);
+    }
+
+    pub(crate) fn insert(&mut self, order: &[usize], tuple: &[Value], timestamp: u32) {
+        debug_assert_eq!(order.len(), tuple.len());
         let mut trie = self;
-        for i in shuffle {
-            trie = trie.0.entry(tuple[*i].clone()).or_default();
+        trie.stamp(timestamp);
+        for i in order {
+            trie = trie.children.entry(tuple[*i].clone()).or_default();
+            trie.stamp(timestamp);
+        }
+    }
+
+    /// Removes a tuple, pruning any nodes that become empty.
+    pub(crate) fn remove(&mut self, order: &[usize], tuple: &[Value]) {
+        if let Some((i, rest)) = order.split_first() {
+            if let Some(child) = self.children.get_mut(&tuple[*i]) {
+                child.remove(rest, tuple);
+                if rest.is_empty() || child.children.is_empty() {
+                    self.children.remove(&tuple[*i]);
+                }
+            }
         }
     }
 }
@@ -49,17 +124,237 @@ impl Trie {
 // for each var, says which atoms contain it
 type VarOccurences = Vec<Vec<usize>>;
 
+/// How a filter computes its output from its inputs.
+#[derive(Debug, Clone)]
+enum FilterOp {
+    Prim(Symbol, Primitive),
+    /// Looks up the inputs in the table of the query's aggregate with this index.
+    Aggregate(usize),
+}
+
+/// A primitive or aggregate evaluated during the join, once its inputs are bound.
+#[derive(Debug, Clone)]
+struct Filter {
+    op: FilterOp,
+    inputs: Vec<AtomTerm>,
+    output: AtomTerm,
+    /// Whether this binds the output variable, rather than checking it.
+    binds: bool,
+}
+
+/// An aggregate's query, planned along with the query it's in.
+#[derive(Debug, Clone)]
+struct CompiledAggregate {
+    expr: Expr,
+    op: AggregateOp,
+    plan: CompiledQuery,
+    /// The terms of the aggregate's query that it's grouped by.
+    keys: Vec<AtomTerm>,
+    /// The term of the aggregate's query that it aggregates.
+    var: AtomTerm,
+    empty: Option<Value>,
+}
+
+/// The result of an aggregate for each group that has any matches,
+/// or None if it has no result, like a sum that overflows.
+type AggregateTable = HashMap<Vec<Value>, Option<Value>>;
+
+#[derive(Debug, Clone)]
 pub struct CompiledQuery {
     atoms: Vec<Atom>,
     var_order: Vec<IndexVar>,
     occurences: VarOccurences,
+    /// For each atom, the order of the columns in the index it uses:
+    /// constants first, then the variables in `var_order`.
+    /// Columns with the same variable are adjacent.
+    orders: Vec<Vec<usize>>,
+    /// The size of each atom's function when the query was planned.
+    sizes: Vec<usize>,
+    /// The estimated number of partial matches after binding each variable in `var_order`.
+    estimates: Vec<f64>,
+    /// For each depth of the join, including the last,
+    /// the primitives and aggregates whose inputs are bound by then.
+    filters: Vec<Vec<Filter>>,
+    aggregates: Vec<CompiledAggregate>,
+    /// For each depth of the join, the negated facts whose variables are bound by then.
+    negations: Vec<Vec<Fact>>,
+    bindings: HashMap<Symbol, AtomTerm>,
+}
+
+impl Display for CompiledQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (depth, (v, estimate)) in self.var_order.iter().zip(&self.estimates).enumerate() {
+            if depth > 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "?{v} (~{estimate:.0})")?;
+        }
+        Ok(())
+    }
+}
+
+/// How a query was compiled and planned, along with the sizes of the
+/// intermediate results from running it once. Made by [`EGraph::explain_query`].
+pub struct QueryExplanation {
+    query: Query,
+    plan: CompiledQuery,
+    /// The actual number of partial matches at each depth of the variable order.
+    counts: Vec<usize>,
+    n_matches: usize,
+}
+
+impl QueryExplanation {
+    pub fn n_matches(&self) -> usize {
+        self.n_matches
+    }
+}
+
+impl Display for QueryExplanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let plan = &self.plan;
+        writeln!(f, "atoms:")?;
+        for (atom, order) in plan.atoms.iter().zip(&plan.orders) {
+            write!(f, "  {atom} with index order {order:?}")?;
+            // constants are looked up before the join starts,
+            // and repeated variables are matched on adjacent columns of the index
+            let mut constraints = vec![];
+            for (i, t) in atom.1.iter().enumerate() {
+                match t {
+                    AtomTerm::Value(val) => constraints.push(format!("#{i} = {val}")),
+                    AtomTerm::Var(_) => {
+                        if let Some(j) = atom.1[..i].iter().position(|t2| t == t2) {
+                            constraints.push(format!("#{j} = #{i}"));
+                        }
+                    }
+                }
+            }
+            if !constraints.is_empty() {
+                write!(f, ", constraints {}", ListDisplay(&constraints, ", "))?;
+            }
+            writeln!(f)?;
+        }
+
+        if plan.filters.iter().any(|fs| !fs.is_empty()) {
+            writeln!(f, "primitives and aggregates:")?;
+            for (depth, filters) in plan.filters.iter().enumerate() {
+                for filter in filters {
+                    let inputs = ListDisplay(&filter.inputs, " ");
+                    match &filter.op {
+                        FilterOp::Prim(op, _) if filter.inputs.is_empty() => {
+                            write!(f, "  {} = ({op})", filter.output)?
+                        }
+                        FilterOp::Prim(op, _) => {
+                            write!(f, "  {} = ({op} {inputs})", filter.output)?
+                        }
+                        FilterOp::Aggregate(i) => {
+                            let expr = &plan.aggregates[*i].expr;
+                            write!(f, "  {} = {expr} grouped by [{inputs}]", filter.output)?
+                        }
+                    }
+                    let verb = if filter.binds { "binding" } else { "checking" };
+                    write!(f, ", {verb} the output ")?;
+                    match depth.checked_sub(1) {
+                        Some(d) => writeln!(f, "after ?{}", plan.var_order[d])?,
+                        None => writeln!(f, "before the join")?,
+                    }
+                }
+            }
+        }
+
+        if plan.negations.iter().any(|ns| !ns.is_empty()) {
+            writeln!(f, "negations:")?;
+            for (depth, negations) in plan.negations.iter().enumerate() {
+                for fact in negations {
+                    write!(f, "  {fact}, looked up ")?;
+                    match depth.checked_sub(1) {
+                        Some(d) => writeln!(f, "after ?{}", plan.var_order[d])?,
+                        None => writeln!(f, "before the join")?,
+                    }
+                }
+            }
+        }
+
+        writeln!(f, "bindings:")?;
+        let mut bindings: Vec<_> = self.query.bindings.iter().collect();
+        bindings.sort_by_key(|(var, _)| var.as_str());
+        for (var, term) in bindings {
+            writeln!(f, "  {var} = {term}")?;
+        }
+
+        writeln!(f, "variable order:")?;
+        writeln!(
+            f,
+            "  {:<8} {:>12} {:>12}",
+            "variable", "estimated", "actual"
+        )?;
+        for ((v, estimate), count) in plan.var_order.iter().zip(&plan.estimates).zip(&self.counts) {
+            writeln!(f, "  {:<8} {estimate:>12.1} {count:>12}", format!("?{v}"))?;
+        }
+        write!(f, "matches: {}", self.n_matches)
+    }
 }
 
 impl EGraph {
-    pub(crate) fn compile_gj_query(&self, atoms: &[Atom]) -> CompiledQuery {
+    /// Compiles, plans, and runs the query once,
+    /// describing each step of how it was evaluated.
+    pub fn explain_query(&mut self, facts: Vec<Fact>) -> Result<QueryExplanation, Error> {
+        let query = self.compile_query(facts)?;
+        let plan = self.compile_gj_query(&query);
+        self.build_indexes(&plan);
+
+        let timestamps = vec![0..u32::MAX; plan.atoms.len()];
+        let tables = self.aggregate_tables(&plan);
+        let mut counts = vec![0; plan.var_order.len()];
+        let mut n_matches = 0;
+        self.run_query_with_timestamps(&plan, &timestamps, &tables, &mut counts, &mut |_| {
+            n_matches += 1
+        });
+        Ok(QueryExplanation {
+            query,
+            plan,
+            counts,
+            n_matches,
+        })
+    }
+
+    pub(crate) fn compile_gj_query(&self, query: &Query) -> CompiledQuery {
+        let atoms = &query.atoms;
+        let aggregates: Vec<CompiledAggregate> = (query.aggregates.iter())
+            .map(|agg| CompiledAggregate {
+                expr: agg.expr.clone(),
+                op: agg.op,
+                plan: self.compile_gj_query(&agg.query),
+                keys: agg
+                    .keys
+                    .iter()
+                    .map(|k| agg.query.bindings[k].clone())
+                    .collect(),
+                var: agg.query.bindings[&agg.var].clone(),
+                empty: agg.empty.clone(),
+            })
+            .collect();
+        let candidates: Vec<Filter> = (query.prims.iter())
+            .map(|prim| Filter {
+                op: FilterOp::Prim(prim.0, prim.1.clone()),
+                inputs: prim.inputs().to_vec(),
+                output: prim.output().clone(),
+                binds: false,
+            })
+            .chain(query.aggregates.iter().enumerate().map(|(i, agg)| Filter {
+                op: FilterOp::Aggregate(i),
+                inputs: agg.keys.iter().map(|k| query.bindings[k].clone()).collect(),
+                output: query.bindings[&agg.output].clone(),
+                binds: false,
+            }))
+            .collect();
         let n_vars = atoms
             .iter()
             .flat_map(|a| a.vars())
+            .chain(query.prims.iter().flat_map(|p| p.vars()))
+            .chain(query.bindings.values().filter_map(|t| match t {
+                AtomTerm::Var(v) => Some(*v),
+                AtomTerm::Value(_) => None,
+            }))
             .max()
             .map_or(0, |v| v + 1);
         let mut occurences = vec![vec![]; n_vars];
@@ -71,102 +366,371 @@ impl EGraph {
             }
         }
 
-        for (v, occs) in occurences.iter().enumerate() {
-            assert!(!occs.is_empty(), "var {} has no occurences", v)
+        // variables that aren't in any atom are computed by primitives
+        let n_joined_vars = occurences.iter().filter(|occs| !occs.is_empty()).count();
+
+        let mut column_sizes = HashMap::<Symbol, Vec<usize>>::default();
+        let sizes: Vec<usize> = atoms.iter().map(|a| self.functions[&a.0].len()).collect();
+        for atom in atoms {
+            column_sizes
+                .entry(atom.0)
+                .or_insert_with(|| self.functions[&atom.0].column_sizes());
         }
 
-        let mut var_order: Vec<IndexVar> = (0..n_vars).collect();
+        // Greedily bind the variable with the fewest expected values next.
+        // For each atom containing it, we assume the columns are independent:
+        // binding a column with `d` distinct values leaves `1/d` of the tuples.
+        let mut var_order: Vec<IndexVar> = vec![];
+        let mut estimates = vec![];
+        let mut estimate = 1.0;
+        let mut is_bound = vec![false; n_vars];
+        while var_order.len() < n_joined_vars {
+            let expected_values = |v: IndexVar| -> f64 {
+                occurences[v]
+                    .iter()
+                    .map(|&j| {
+                        let atom = &atoms[j];
+                        let distinct = &column_sizes[&atom.0];
+                        // empty functions would make every estimate zero
+                        let mut matching = sizes[j].max(1) as f64;
+                        for (i, t) in atom.1.iter().enumerate() {
+                            let bound = match t {
+                                AtomTerm::Value(_) => true,
+                                AtomTerm::Var(u) => is_bound[*u],
+                            };
+                            if bound {
+                                matching /= distinct[i].max(1) as f64;
+                            }
+                        }
+                        let i = atom.1.iter().position(|t| t == &AtomTerm::Var(v)).unwrap();
+                        matching.min(distinct[i].max(1) as f64)
+                    })
+                    .fold(f64::INFINITY, f64::min)
+            };
+            let (_, v, values) = (0..n_vars)
+                .filter(|&v| !is_bound[v] && !occurences[v].is_empty())
+                .map(|v| {
+                    (
+                        std::cmp::Reverse(occurences[v].len()),
+                        v,
+                        expected_values(v),
+                    )
+                })
+                .min_by(|a, b| a.2.total_cmp(&b.2).then(a.0.cmp(&b.0)))
+                .unwrap();
+            is_bound[v] = true;
+            var_order.push(v);
+            estimate *= values;
+            estimates.push(estimate);
+        }
+
+        let orders = atoms
+            .iter()
+            .map(|atom| {
+                let mut order: Vec<usize> = (0..atom.1.len())
+                    .filter(|&i| matches!(atom.1[i], AtomTerm::Value(_)))
+                    .collect();
+                for &v in &var_order {
+                    order.extend((0..atom.1.len()).filter(|&i| atom.1[i] == AtomTerm::Var(v)));
+                }
+                order
+            })
+            .collect();
 
-        // simple variable ordering for now
-        var_order.sort_unstable_by_key(|&v| -(occurences[v].len() as i32));
+        // Each primitive or aggregate runs as soon as its inputs are bound.
+        // If its output isn't in any atom, the first one to compute it binds it,
+        // otherwise it waits until the output is bound and checks it.
+        // Negations are looked up as soon as all their variables are bound.
+        let mut filters = vec![vec![]; var_order.len() + 1];
+        let mut negations = vec![vec![]; var_order.len() + 1];
+        let mut is_bound = vec![false; n_vars];
+        let mut pending: Vec<Filter> = candidates;
+        let mut pending_negations: Vec<&Fact> = query.negations.iter().collect();
+        for (depth, (filters, negations)) in filters.iter_mut().zip(&mut negations).enumerate() {
+            if depth > 0 {
+                is_bound[var_order[depth - 1]] = true;
+            }
+            let term_is_bound = |is_bound: &[bool], t: &AtomTerm| match t {
+                AtomTerm::Var(v) => is_bound[*v],
+                AtomTerm::Value(_) => true,
+            };
+            loop {
+                let n_pending = pending.len();
+                pending.retain(|filter| {
+                    if !filter.inputs.iter().all(|t| term_is_bound(&is_bound, t)) {
+                        return true;
+                    }
+                    let binds = match &filter.output {
+                        AtomTerm::Var(v) if !is_bound[*v] => {
+                            if !occurences[*v].is_empty() {
+                                return true;
+                            }
+                            is_bound[*v] = true;
+                            true
+                        }
+                        _ => false,
+                    };
+                    filters.push(Filter {
+                        binds,
+                        ..filter.clone()
+                    });
+                    false
+                });
+                if pending.len() == n_pending {
+                    break;
+                }
+            }
+            pending_negations.retain(|&fact| {
+                let mut ready = true;
+                fact.for_each_expr(&mut |e| {
+                    e.walk(
+                        &mut |e| {
+                            if let Expr::Var(_, var) = e {
+                                if let AtomTerm::Var(v) = query.bindings[var] {
+                                    ready &= is_bound[v];
+                                }
+                            }
+                        },
+                        &mut |_| {},
+                    )
+                });
+                if ready {
+                    negations.push(fact.clone());
+                }
+                !ready
+            });
+        }
+        assert!(
+            pending.is_empty(),
+            "primitives or aggregates with unbound inputs"
+        );
+        assert!(
+            pending_negations.is_empty(),
+            "negations with unbound variables"
+        );
 
         CompiledQuery {
-            atoms: atoms.into(),
+            atoms: atoms.clone(),
             var_order,
             occurences,
+            orders,
+            sizes,
+            estimates,
+            filters,
+            aggregates,
+            negations,
+            bindings: query.bindings.clone(),
         }
     }
 
-    fn build_trie(
-        &self,
-        relation: Symbol,
-        projection: &[usize],
-        constraints: &[Constraint],
-    ) -> Trie {
-        let mut trie = Trie::default();
-        if constraints.is_empty() {
-            self.for_each_canonicalized(relation, |tuple| {
-                trie.insert(projection, tuple);
-            });
-        } else {
-            self.for_each_canonicalized(relation, |tuple| {
-                for constraint in constraints {
-                    let ok = match constraint {
-                        Constraint::Eq(i, j) => tuple[*i] == tuple[*j],
-                        Constraint::Const(i, t) => &tuple[*i] == t,
-                    };
-                    if ok {
-                        trie.insert(projection, tuple);
-                    }
-                }
-            });
+    /// Whether any function in the query has grown or shrunk
+    /// by more than a factor of two since the query was planned.
+    pub(crate) fn plan_is_stale(&self, query: &CompiledQuery) -> bool {
+        let is_stale = query.atoms.iter().zip(&query.sizes).any(|(atom, &old)| {
+            let old = old.max(1);
+            let new = self.functions[&atom.0].len().max(1);
+            new > 2 * old || 2 * new < old
+        });
+        is_stale || (query.aggregates.iter()).any(|agg| self.plan_is_stale(&agg.plan))
+    }
+
+    pub(crate) fn build_indexes(&mut self, query: &CompiledQuery) {
+        for agg in &query.aggregates {
+            self.build_indexes(&agg.plan);
+        }
+        for (atom, order) in query.atoms.iter().zip(&query.orders) {
+            self.functions
+                .get_mut(&atom.0)
+                .unwrap_or_else(|| panic!("No function {}", atom.0))
+                .build_index(order);
         }
-        trie
     }
 
-    pub(crate) fn run_query<F>(&self, query: &CompiledQuery, mut f: F)
+    /// Runs the query, calling `f` on every match that uses at least one tuple
+    /// stamped at or after `timestamp`.
+    ///
+    /// This is semi-naive evaluation: for each atom `i`, we find the matches where
+    /// atom `i` is new, the atoms before it are old, and the atoms after it are
+    /// unrestricted. Every such match is found exactly once.
+    pub(crate) fn run_query<F>(&self, query: &CompiledQuery, timestamp: u32, mut f: F)
     where
         F: FnMut(&[Value]),
     {
-        log::debug!("Eval {:?}", query.atoms);
-        let tries = query
-            .atoms
-            .iter()
-            .map(|atom| {
-                let mut to_project = vec![];
-                let mut constraints = vec![];
-                for (i, t) in atom.1.iter().enumerate() {
-                    match t {
-                        AtomTerm::Value(val) => constraints.push(Constraint::Const(i, val.clone())),
-                        AtomTerm::Var(v) => {
-                            if let Some(j) = atom.1[..i].iter().position(|t2| t == t2) {
-                                constraints.push(Constraint::Eq(j, i));
-                            } else {
-                                to_project.push(v)
-                            }
-                        }
-                    }
-                }
+        let tables = self.aggregate_tables(query);
+        let mut counts = vec![0; query.var_order.len()];
+        let all = 0..u32::MAX;
+        if timestamp == 0 {
+            let timestamps = vec![all; query.atoms.len()];
+            return self.run_query_with_timestamps(
+                query,
+                &timestamps,
+                &tables,
+                &mut counts,
+                &mut f,
+            );
+        }
+
+        let mut timestamps = vec![all; query.atoms.len()];
+        for i in 0..query.atoms.len() {
+            timestamps[i] = timestamp..u32::MAX;
+            self.run_query_with_timestamps(query, &timestamps, &tables, &mut counts, &mut f);
+            timestamps[i] = 0..timestamp;
+        }
+    }
 
-                let mut projection = vec![];
-                for v in &query.var_order {
-                    if let Some(i) = atom.1.iter().position(|t| t == &AtomTerm::Var(*v)) {
-                        assert!(!projection.contains(&i));
-                        projection.push(i);
+    /// Runs the query of each aggregate, grouping and combining its matches.
+    fn aggregate_tables(&self, query: &CompiledQuery) -> Vec<AggregateTable> {
+        (query.aggregates.iter())
+            .map(|agg| {
+                let mut groups = HashMap::<Vec<Value>, Vec<Value>>::default();
+                self.run_query(&agg.plan, 0, |tuple| {
+                    let get = |t: &AtomTerm| match t {
+                        AtomTerm::Var(v) => tuple[*v].clone(),
+                        AtomTerm::Value(val) => val.clone(),
+                    };
+                    let key = agg.keys.iter().map(get).collect();
+                    groups.entry(key).or_default().push(get(&agg.var));
+                });
+                (groups.into_iter())
+                    .map(|(key, values)| (key, agg.op.reduce(values)))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Runs the query over tuples in the given timestamp range for each atom,
+    /// adding the number of partial matches at each depth to `counts`.
+    fn run_query_with_timestamps<F>(
+        &self,
+        query: &CompiledQuery,
+        timestamps: &[Range<u32>],
+        tables: &[AggregateTable],
+        counts: &mut [usize],
+        f: &mut F,
+    ) where
+        F: FnMut(&[Value]),
+    {
+        log::debug!("Eval {:?}", query.atoms);
+        let mut relations = Vec::with_capacity(query.atoms.len());
+        for ((atom, order), timestamps) in query.atoms.iter().zip(&query.orders).zip(timestamps) {
+            let mut trie = self.functions[&atom.0].index(order);
+            // the constants come first in the index, so we can look them up now
+            for &i in order {
+                if let AtomTerm::Value(val) = &atom.1[i] {
+                    match trie.get(val, timestamps) {
+                        Some(t) => trie = t,
+                        None => return,
                     }
                 }
+            }
+            relations.push(trie);
+        }
 
-                self.build_trie(atom.0, &projection, &constraints)
-            })
-            .collect::<Vec<_>>();
+        let mut tuple = vec![Value::fake(); query.occurences.len()];
+        self.gj(
+            0, query, timestamps, tables, counts, f, &mut tuple, &relations,
+        );
+    }
 
-        let tries: Vec<&Trie> = tries.iter().collect();
+    /// Whether a fact holds, looking up what's already in the database.
+    fn holds(&self, fact: &Fact, get_var: &impl Fn(Symbol) -> Value) -> bool {
+        match fact {
+            Fact::Eq(_, exprs) => {
+                let first = self.lookup_expr(&exprs[0], get_var);
+                first.is_some()
+                    && exprs[1..]
+                        .iter()
+                        .all(|e| self.lookup_expr(e, get_var) == first)
+            }
+            // a relation holds if the tuple is there, and a guard if it's true
+            Fact::Fact(expr) => self
+                .lookup_expr(expr, get_var)
+                .is_some_and(|v| v != Value::from(false)),
+            Fact::Not(_, fact) => !self.holds(fact, get_var),
+        }
+    }
 
-        let tuple = vec![Value::fake(); query.var_order.len()];
-        self.gj(0, query, &mut f, &tuple, &tries);
+    /// Evaluates an expression without adding anything to the database,
+    /// returning None if some function call isn't there.
+    pub(crate) fn lookup_expr(
+        &self,
+        expr: &Expr,
+        get_var: &impl Fn(Symbol) -> Value,
+    ) -> Option<Value> {
+        match expr {
+            Expr::Lit(_, lit) => Some(lit.to_value()),
+            Expr::Var(_, var) => Some(get_var(*var)),
+            Expr::Call(_, op, args) => {
+                let args: Vec<Value> = args
+                    .iter()
+                    .map(|arg| self.lookup_expr(arg, get_var))
+                    .collect::<Option<_>>()?;
+                if let Some(function) = self.functions.get(op) {
+                    let value = function.get(&args)?.clone();
+                    Some(self.bad_find_value(value))
+                } else {
+                    let prims = &self.primitives[op];
+                    let prim = prims.iter().find(|p| p.accept(&args))?;
+                    prim.apply(&args)
+                }
+            }
+        }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn gj<F>(
         &self,
         depth: usize,
         query: &CompiledQuery,
+        timestamps: &[Range<u32>],
+        tables: &[AggregateTable],
+        counts: &mut [usize],
         f: &mut F,
-        tuple: &[Value],
+        tuple: &mut [Value],
         relations: &[&Trie],
     ) where
         F: FnMut(&[Value]),
     {
-        // log::debug!("{:?}", tuple);
+        for filter in &query.filters[depth] {
+            let get = |t: &AtomTerm| match t {
+                AtomTerm::Var(v) => tuple[*v].clone(),
+                AtomTerm::Value(val) => val.clone(),
+            };
+            let inputs: Vec<Value> = filter.inputs.iter().map(get).collect();
+            let output = match &filter.op {
+                FilterOp::Prim(_, prim) => match prim.apply(&inputs) {
+                    Some(value) => value,
+                    None => return,
+                },
+                FilterOp::Aggregate(i) => {
+                    let empty = query.aggregates[*i].empty.as_ref();
+                    match tables[*i].get(&inputs).map_or(empty, Option::as_ref) {
+                        Some(value) => value.clone(),
+                        None => return,
+                    }
+                }
+            };
+            match &filter.output {
+                AtomTerm::Var(v) if filter.binds => tuple[*v] = output,
+                t => {
+                    if get(t) != output {
+                        return;
+                    }
+                }
+            }
+        }
+
+        let get_var = |var: Symbol| match &query.bindings[&var] {
+            AtomTerm::Var(v) => tuple[*v].clone(),
+            AtomTerm::Value(val) => val.clone(),
+        };
+        if !query.negations[depth]
+            .iter()
+            .all(|fact| self.holds(fact, &get_var))
+        {
+            return;
+        }
+
         if depth == query.var_order.len() {
             return f(tuple);
         }
@@ -184,38 +748,32 @@ impl EGraph {
             .min_by_key(|j| relations[*j].len())
             .unwrap();
 
-        // for &j in js {
-        //     log::debug!("{:?}", relations[j].0.keys());
-        // }
-
-        let mut intersection: Vec<Value> = relations[j_min].0.keys().cloned().collect();
-
-        for &j in js {
-            if j != j_min {
-                let rj = &relations[j].0;
-                intersection.retain(|t| rj.contains_key(t));
-            }
-        }
-
-        // log::debug!("intersection of {:?}: {:?}", x, intersection);
-
-        let empty = Trie::default();
-
-        let mut tuple = tuple.to_vec();
-        for val in intersection {
-            let relations: Vec<_> = relations
-                .iter()
-                .zip(&query.atoms)
-                .map(|(r, a)| {
-                    if a.1.contains(&AtomTerm::Var(x)) {
-                        r.0.get(&val).unwrap_or(&empty)
-                    } else {
-                        r
+        let mut next_relations = relations.to_vec();
+        'candidates: for val in relations[j_min].children.keys() {
+            for &j in js {
+                // a variable may appear in several adjacent columns of an atom
+                let n_cols = query.atoms[j].1.iter().filter(|t| **t == AtomTerm::Var(x));
+                let mut trie = relations[j];
+                for _ in n_cols {
+                    match trie.get(val, &timestamps[j]) {
+                        Some(t) => trie = t,
+                        None => continue 'candidates,
                     }
-                })
-                .collect();
-            tuple[x] = val;
-            self.gj(depth + 1, query, f, &tuple, &relations);
+                }
+                next_relations[j] = trie;
+            }
+            tuple[x] = val.clone();
+            counts[depth] += 1;
+            self.gj(
+                depth + 1,
+                query,
+                timestamps,
+                tables,
+                counts,
+                f,
+                tuple,
+                &next_relations,
+            );
         }
     }
 }
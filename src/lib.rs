@@ -1,6 +1,12 @@
+#![allow(clippy::result_large_err)]
+mod aggregate;
 pub mod ast;
 mod extract;
+mod function;
 mod gj;
+mod proofs;
+mod scheduler;
+mod stratify;
 mod typecheck;
 mod unionfind;
 mod util;
@@ -12,79 +18,57 @@ use thiserror::Error;
 use ast::*;
 use std::fmt::Debug;
 use std::hash::Hash;
-
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub use extract::{Cost, CostFunction};
+use extract::{Extractor, DEFAULT_DAG_TIME_LIMIT};
+pub use function::Function;
+pub use gj::QueryExplanation;
+pub use proofs::{Explanation, ProofReason, ProofStep};
+pub use scheduler::{BackoffScheduler, Scheduler, SimpleScheduler};
+pub use typecheck::TypeError;
 pub use value::*;
 
+use aggregate::*;
 use gj::*;
 use num_rational::BigRational;
+use proofs::*;
+use stratify::*;
 use unionfind::*;
 use util::*;
 
-use crate::typecheck::TypeError;
-
-type PrimFn = fn(&[Value]) -> Value;
-
-#[derive(Clone)]
-pub struct Function {
-    decl: FunctionDecl,
-    nodes: HashMap<Vec<Value>, Value>,
-    updates: usize,
-}
-
-impl Function {
-    pub fn new(decl: FunctionDecl) -> Self {
-        Self {
-            decl,
-            nodes: Default::default(),
-            updates: 0,
-        }
-    }
-
-    pub fn rebuild(&mut self, uf: &mut UnionFind) -> usize {
-        // FIXME this doesn't compute updates properly
-        let n_unions = uf.n_unions();
-        let old_nodes = std::mem::take(&mut self.nodes);
-        for (mut args, value) in old_nodes {
-            for (a, ty) in args.iter_mut().zip(&self.decl.schema.input) {
-                if ty.is_sort() {
-                    *a = uf.find_mut_value(a.clone())
-                }
-            }
-            let _new_value = if self.decl.schema.output.is_sort() {
-                self.nodes
-                    .entry(args)
-                    .and_modify(|value2| *value2 = uf.union_values(value.clone(), value2.clone()))
-                    .or_insert_with(|| uf.find_mut_value(value))
-            } else {
-                self.nodes
-                    .entry(args)
-                    // .and_modify(|value2| *value2 = uf.union_values(value.clone(), value2.clone()))
-                    .or_insert(value)
-            };
-        }
-        uf.n_unions() - n_unions + std::mem::take(&mut self.updates)
-    }
-}
+/// The implementation of a primitive, which returns None when it has no result
+/// for its inputs, like a division by zero.
+type PrimFn = Arc<dyn Fn(&[Value]) -> Option<Value> + Send + Sync>;
 
 pub type Subst = IndexMap<Symbol, Value>;
 
 #[derive(Clone)]
-#[allow(dead_code)]
 pub struct Primitive {
-    input: Vec<NumType>,
-    output: NumType,
+    input: Vec<Type>,
+    output: Type,
     f: PrimFn,
 }
 
+impl Debug for Primitive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Primitive")
+            .field("input", &self.input)
+            .field("output", &self.output)
+            .finish_non_exhaustive()
+    }
+}
+
 impl Primitive {
     pub fn accept(&self, values: &[Value]) -> bool {
-        self.input
-            .iter()
-            .zip(values.iter())
-            .all(|(t, v)| matches!(v.get_type(), Type::NumType(t1) if &t1 == t))
+        self.input.len() == values.len()
+            && (self.input.iter().zip(values.iter()))
+                .all(|(t, v)| !matches!(v.0, ValueInner::Id(_)) && &v.get_type() == t)
     }
 
-    pub fn apply(&self, values: &[Value]) -> Value {
+    pub fn apply(&self, values: &[Value]) -> Option<Value> {
         (self.f)(values)
     }
 }
@@ -93,40 +77,71 @@ fn default_primitives() -> HashMap<Symbol, Vec<Primitive>> {
     macro_rules! prim {
         (@type I64) => { i64 };
         (@type Rational) => { BigRational };
+        (@type String) => { Symbol };
+        (@type Bool) => { bool };
+        (@ty I64) => { Type::NumType(NumType::I64) };
+        (@ty Rational) => { Type::NumType(NumType::Rational) };
+        (@ty String) => { Type::String };
+        (@ty Bool) => { Type::Bool };
+        // partial primitives return None when there is no result
+        (|$($param:ident : $t:ident),*| -> Option<$output:ident> { $body:expr }) => {
+            Primitive {
+                input: vec![$(prim!(@ty $t)),*],
+                output: prim!(@ty $output),
+                f: Arc::new(|values: &[Value]| {
+                    let mut values = values.iter();
+                    $(
+                        let $param: prim!(@type $t) = values.next().unwrap().clone().into();
+                    )*
+                    $body.map(Value::from)
+                }),
+            }
+        };
         (|$($param:ident : $t:ident),*| -> $output:ident { $body:expr }) => {
             Primitive {
-                input: vec![$(NumType::$t),*],
-                output: NumType::$output,
-                f: |values: &[Value]| -> Value {
+                input: vec![$(prim!(@ty $t)),*],
+                output: prim!(@ty $output),
+                f: Arc::new(|values: &[Value]| {
                     let mut values = values.iter();
                     $(
                         let $param: prim!(@type $t) = values.next().unwrap().clone().into();
                     )*
-                    Value::from($body)
-                }
+                    Some(Value::from($body))
+                }),
             }
         };
     }
 
+    // compares numbers by value and strings alphabetically
+    macro_rules! comparison {
+        ($op:tt) => {
+            vec![
+                prim!(|a: I64, b: I64| -> Bool { a $op b }),
+                prim!(|a: Rational, b: Rational| -> Bool { a $op b }),
+                prim!(|a: String, b: String| -> Bool { a.as_str() $op b.as_str() }),
+            ]
+        };
+    }
+
     [
         (
             "+",
             vec![
-                prim!(|a: I64, b: I64| -> I64 { a + b }),
+                prim!(|a: I64, b: I64| -> Option<I64> { a.checked_add(b) }),
                 prim!(|a: Rational, b: Rational| -> Rational { a + b }),
             ],
         ),
         (
             "-",
             vec![
-                prim!(|a: I64, b: I64| -> I64 { a - b }),
+                prim!(|a: I64, b: I64| -> Option<I64> { a.checked_sub(b) }),
                 prim!(|a: Rational, b: Rational| -> Rational { a - b }),
             ],
         ),
         (
             "*",
             vec![
-                prim!(|a: I64, b: I64| -> I64 { a * b }),
+                prim!(|a: I64, b: I64| -> Option<I64> { a.checked_mul(b) }),
                 prim!(|a: Rational, b: Rational| -> Rational { a * b }),
             ],
         ),
@@ -144,6 +159,23 @@ fn default_primitives() -> HashMap<Symbol, Vec<Primitive>> {
                 prim!(|a: Rational, b: Rational| -> Rational { a.min(b) }),
             ],
         ),
+        ("<", comparison!(<)),
+        ("<=", comparison!(<=)),
+        (">", comparison!(>)),
+        (">=", comparison!(>=)),
+        ("=", {
+            let mut prims = comparison!(==);
+            prims.push(prim!(|a: Bool, b: Bool| -> Bool { a == b }));
+            prims
+        }),
+        ("!=", {
+            let mut prims = comparison!(!=);
+            prims.push(prim!(|a: Bool, b: Bool| -> Bool { a != b }));
+            prims
+        }),
+        ("and", vec![prim!(|a: Bool, b: Bool| -> Bool { a && b })]),
+        ("or", vec![prim!(|a: Bool, b: Bool| -> Bool { a || b })]),
+        ("not", vec![prim!(|a: Bool| -> Bool { !a })]),
     ]
     .into_iter()
     .map(|(k, v)| (Symbol::from(k), v))
@@ -157,13 +189,131 @@ pub struct EGraph {
     primitives: HashMap<Symbol, Vec<Primitive>>,
     functions: HashMap<Symbol, Function>,
     rules: HashMap<Symbol, Rule>,
+    /// The declared rulesets, including the default (empty) one.
+    rulesets: HashSet<Symbol>,
     globals: HashMap<Symbol, Value>,
+    /// The types of the globals, recorded when their definitions are typechecked.
+    global_types: HashMap<Symbol, Type>,
+    /// Stamped on tuples when they are added or changed,
+    /// incremented after every search of the rules.
+    timestamp: u32,
+    scheduler: Box<dyn Scheduler>,
+    /// How extracted terms are costed, if not by the `:cost` of their constructors.
+    cost_function: Option<Arc<dyn CostFunction>>,
+    run_config: RunConfig,
+    /// With proofs enabled, the justification for unions made by the
+    /// actions being evaluated.
+    reason: Justification,
+}
+
+// an EGraph can be moved to another thread, which its trait objects mustn't prevent
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<EGraph>();
+};
+
+/// The outcome of running the rules with [`EGraph::run_rules`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunReport {
+    /// The number of iterations that were run.
+    pub iterations: usize,
+    /// Whether the last iteration made no changes to the database
+    /// and the scheduler agreed to stop.
+    pub saturated: bool,
+    /// The total number of new tuples, changed outputs, and unions.
+    pub updates: usize,
+    /// The limit from the [`RunConfig`] that stopped the run, if any.
+    pub stop_reason: Option<StopReason>,
+}
+
+/// Limits on running the rules, set with [`EGraph::set_run_config`].
+/// They are checked before every iteration, before searching each rule,
+/// and after applying each match, so a run stops part way through an
+/// iteration if need be. Counting the tuples takes a pass over the functions,
+/// so the node limit is only checked before searching and after applying
+/// each rule. The database is rebuilt before the run returns.
+#[derive(Clone, Debug, Default)]
+pub struct RunConfig {
+    /// Stop once the functions hold more than this many tuples in total.
+    pub node_limit: Option<usize>,
+    /// Stop once the union-find holds more than this many ids.
+    pub size_limit: Option<usize>,
+    /// Stop once a run (or a whole schedule) has taken longer than this.
+    /// Ignored with a warning on wasm, which has no clock.
+    pub time_limit: Option<Duration>,
+    /// Stop once this is set, for example from another thread.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+/// Why a run stopped before saturating or reaching its iteration limit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    NodeLimit(usize),
+    SizeLimit(usize),
+    TimeLimit(Duration),
+    Cancelled,
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopReason::NodeLimit(n) => write!(f, "node limit of {n} tuples"),
+            StopReason::SizeLimit(n) => write!(f, "union-find limit of {n} ids"),
+            StopReason::TimeLimit(t) => write!(f, "time limit of {t:?}"),
+            StopReason::Cancelled => write!(f, "cancellation"),
+        }
+    }
+}
+
+impl std::fmt::Display for RunReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Ran {} iterations", self.iterations)?;
+        if self.saturated {
+            write!(f, ", saturated")?;
+        }
+        if let Some(reason) = &self.stop_reason {
+            write!(f, ", stopped by {reason}")?;
+        }
+        write!(f, " ({} updates).", self.updates)
+    }
+}
+
+impl RunReport {
+    /// Adds on the report of a later run.
+    pub fn add(&mut self, later: RunReport) {
+        self.iterations += later.iterations;
+        self.updates += later.updates;
+        self.saturated = later.saturated;
+        self.stop_reason = later.stop_reason;
+    }
+}
+
+/// Counters for a rule, accumulated over every run since it was added.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RuleStats {
+    /// The number of times the rule was searched.
+    pub searches: usize,
+    /// The number of matches found, including any the scheduler didn't apply.
+    pub matches: usize,
+    /// The number of applied matches whose actions changed the database.
+    pub changes: usize,
+    pub search_time: Duration,
+    pub apply_time: Duration,
 }
 
 #[derive(Clone, Debug)]
 struct Rule {
+    ruleset: Symbol,
     query: Query,
     head: Vec<Action>,
+    /// All matches made only of tuples older than this have already been applied.
+    search_timestamp: u32,
+    /// The plan for the query, made again when the functions change size.
+    plan: Option<CompiledQuery>,
+    stats: RuleStats,
+    deps: Dependencies,
+    /// Rules in lower strata of the ruleset run to saturation first.
+    stratum: usize,
 }
 
 impl Default for EGraph {
@@ -173,8 +323,15 @@ impl Default for EGraph {
             sorts: Default::default(),
             functions: Default::default(),
             rules: Default::default(),
+            rulesets: [Symbol::from("")].into_iter().collect(),
             globals: Default::default(),
+            global_types: Default::default(),
             primitives: default_primitives(),
+            timestamp: 0,
+            scheduler: Box::new(SimpleScheduler),
+            cost_function: None,
+            run_config: Default::default(),
+            reason: Justification::Action("top-level action".into()),
         }
     }
 }
@@ -185,14 +342,17 @@ pub struct NotFoundError(Expr);
 
 impl EGraph {
     pub fn union(&mut self, id1: Id, id2: Id) -> Id {
-        self.unionfind.union(id1, id2)
+        let reason = || Justification::Action(format!("(union {id1} {id2})"));
+        self.unionfind
+            .union_values(id1.into(), id2.into(), reason)
+            .into()
     }
 
     #[track_caller]
     fn debug_assert_invariants(&self) {
         #[cfg(debug_assertions)]
         for (name, function) in self.functions.iter() {
-            for (inputs, output) in function.nodes.iter() {
+            for (inputs, function::TupleOutput { value: output, .. }) in function.nodes.iter() {
                 for input in inputs {
                     assert_eq!(
                         input,
@@ -211,13 +371,16 @@ impl EGraph {
         }
     }
 
-    pub fn union_exprs(&mut self, ctx: &Subst, exprs: &[Expr]) -> Result<Value, NotFoundError> {
+    pub fn union_exprs(&mut self, ctx: &Subst, exprs: &[Expr]) -> Result<Value, Error> {
         let mut exprs = exprs.iter();
-        let e = exprs.next().expect("shouldn't be empty");
+        let Some(e) = exprs.next() else {
+            let fact = Fact::Eq(Span::default(), vec![]);
+            return Err(TypeError::TooFewExprs(fact).into());
+        };
         let mut val = self.eval_expr(ctx, e)?;
         for e2 in exprs {
             let val2 = self.eval_expr(ctx, e2)?;
-            val = self.unionfind.union_values(val, val2);
+            val = (self.unionfind).union_values(val, val2, || self.reason.clone());
         }
         Ok(val)
     }
@@ -230,11 +393,11 @@ impl EGraph {
         let default = Subst::default();
         for action in actions {
             match action {
-                Action::Panic(msg) => panic!("panic {}", msg),
-                Action::Expr(e) => {
+                Action::Panic(_, msg) => return Err(Error::Panic(msg.clone())),
+                Action::Expr(_, e) => {
                     self.eval_expr(ctx.as_ref().unwrap_or(&default), e)?;
                 }
-                Action::Define(x, e) => {
+                Action::Define(_, x, e) => {
                     if let Some(ctx) = ctx.as_mut() {
                         let value = self.eval_expr(ctx, e)?;
                         ctx.insert(*x, value);
@@ -243,9 +406,9 @@ impl EGraph {
                         self.globals.insert(*x, value);
                     }
                 }
-                Action::Set(f, args, e) => {
+                Action::Set(span, f, args, e) => {
                     let ctx = ctx.as_ref().unwrap_or(&default);
-                    let values = args
+                    let mut values = args
                         .iter()
                         .map(|a| self.eval_expr(ctx, a))
                         .collect::<Result<Vec<_>, _>>()?;
@@ -253,35 +416,46 @@ impl EGraph {
                     let function = self
                         .functions
                         .get_mut(f)
-                        .ok_or_else(|| NotFoundError(e.clone()))?;
-                    let old_value = function.nodes.insert(values.clone(), value.clone());
-                    if let Some(old_value) = old_value {
+                        .ok_or(TypeError::Unbound(*f, *span))?;
+                    if self.unionfind.proofs.is_some() {
+                        if function.decl.merge.is_none() && function.decl.schema.output.is_sort() {
+                            // the call is a term of its own, equal to the value
+                            let term = self.make_term(*f, values);
+                            (self.unionfind).union_values(term, value, || self.reason.clone());
+                            continue;
+                        }
+                        values = values.into_iter().map(|v| self.bad_find_value(v)).collect();
+                    }
+                    let function = self.functions.get_mut(f).unwrap();
+                    let new_value = if let Some(old_value) = function.get(&values) {
                         match (function.decl.merge.as_ref(), &function.decl.schema.output) {
-                            (None, Type::Unit) => (),
+                            (None, Type::Unit) => continue,
                             (None, Type::Sort(_)) => {
-                                self.unionfind.union_values(old_value, value);
+                                // the old tuple stays, rebuilding will canonicalize it
+                                let old_value = old_value.clone();
+                                let reason = || self.reason.clone();
+                                self.unionfind.union_values(old_value, value, reason);
+                                continue;
                             }
-                            (Some(expr), _) => {
-                                let mut ctx = Subst::default();
-                                ctx.insert("old".into(), old_value);
-                                ctx.insert("new".into(), value);
-                                let expr = expr.clone(); // break the borrow of `function`
-                                let new_value = self.eval_expr(&ctx, &expr)?;
-                                self.functions
-                                    .get_mut(f)
-                                    .unwrap()
-                                    .nodes
-                                    .insert(values, new_value);
+                            _ => {
+                                let old_value = old_value.clone(); // break the borrow of `function`
+                                self.eval_merge(*f, *span, old_value, value)?
                             }
-                            _ => panic!("invalid merge function"),
                         }
-                    }
+                    } else {
+                        value
+                    };
+                    let timestamp = self.timestamp;
+                    self.functions
+                        .get_mut(f)
+                        .unwrap()
+                        .insert(values, new_value, timestamp);
                 }
-                Action::Union(a, b) => {
+                Action::Union(_, a, b) => {
                     let ctx = ctx.as_ref().unwrap_or(&default);
                     let a = self.eval_expr(ctx, a)?;
                     let b = self.eval_expr(ctx, b)?;
-                    self.unionfind.union_values(a, b);
+                    (self.unionfind).union_values(a, b, || self.reason.clone());
                 }
             }
         }
@@ -290,15 +464,17 @@ impl EGraph {
 
     pub fn check_with(&mut self, ctx: &Subst, fact: &Fact) -> Result<(), Error> {
         match fact {
-            Fact::Eq(exprs) => {
-                assert!(exprs.len() > 1);
+            Fact::Eq(_, exprs) if exprs.len() < 2 => {
+                return Err(TypeError::TooFewExprs(fact.clone()).into());
+            }
+            Fact::Eq(span, exprs) => {
                 let values: Vec<Value> = exprs
                     .iter()
                     .map(|e| self.eval_expr(ctx, e).map(|v| self.bad_find_value(v)))
                     .collect::<Result<_, _>>()?;
                 for v in &values[1..] {
                     if &values[0] != v {
-                        return Err(Error::CheckError(values[0].clone(), v.clone()));
+                        return Err(Error::CheckError(values[0].clone(), v.clone(), *span));
                     }
                 }
                 // let mut should_union = true;
@@ -324,24 +500,31 @@ impl EGraph {
                 // }
             }
             Fact::Fact(expr) => match expr {
-                Expr::Lit(_) => panic!("can't assert a literal"),
-                Expr::Var(_) => panic!("can't assert a var"),
-                Expr::Call(sym, args) => {
+                Expr::Lit(..) | Expr::Var(..) => return Err(Error::InvalidCheck(expr.clone())),
+                Expr::Call(span, sym, args) => {
                     let values: Vec<Value> = args
                         .iter()
                         .map(|e| self.eval_expr(ctx, e))
                         .collect::<Result<_, _>>()?;
-                    let f = self
-                        .functions
-                        .get_mut(sym)
-                        .expect("FIXME add error message");
+                    let f = (self.functions.get_mut(sym)).ok_or(TypeError::Unbound(*sym, *span))?;
+                    if f.decl.schema.output != Type::Unit {
+                        return Err(TypeError::Mismatch {
+                            expr: expr.clone(),
+                            expected: Type::Unit,
+                            actual: f.decl.schema.output.clone(),
+                            reason: "check".into(),
+                        }
+                        .into());
+                    }
                     // FIXME We don't have a unit value
-                    f.nodes
-                        .get(&values)
-                        .ok_or_else(|| NotFoundError(expr.clone()))?;
-                    assert_eq!(f.decl.schema.output, Type::Unit);
+                    f.get(&values).ok_or_else(|| NotFoundError(expr.clone()))?;
                 }
             },
+            Fact::Not(_, inner) => match self.check_with(ctx, inner) {
+                Ok(()) => return Err(Error::NegatedCheckError((**inner).clone())),
+                Err(Error::CheckError(..) | Error::NotFoundError(_)) => {}
+                Err(err) => return Err(err),
+            },
         }
         Ok(())
     }
@@ -350,10 +533,10 @@ impl EGraph {
         self.unionfind.find(id)
     }
 
-    pub fn rebuild(&mut self) -> usize {
+    pub fn rebuild(&mut self) -> Result<usize, Error> {
         let mut updates = 0;
         loop {
-            let new = self.rebuild_one();
+            let new = self.rebuild_one()?;
             log::debug!("{new} rebuilds?");
             updates += new;
             if new == 0 {
@@ -361,15 +544,53 @@ impl EGraph {
             }
         }
         self.debug_assert_invariants();
-        updates
+        Ok(updates)
     }
 
-    fn rebuild_one(&mut self) -> usize {
+    fn rebuild_one(&mut self) -> Result<usize, Error> {
+        let dirty = self.unionfind.take_dirty();
         let mut new_unions = 0;
-        for function in self.functions.values_mut() {
-            new_unions += function.rebuild(&mut self.unionfind);
+        let mut conflicts = vec![];
+        for (name, function) in self.functions.iter_mut() {
+            new_unions += function.rebuild(&mut self.unionfind, &dirty, self.timestamp);
+            conflicts.extend((function.conflicts.drain(..)).map(|c| (*name, c)));
+        }
+        // tuples that collided are merged into the one in the table like a `set`
+        // would, one at a time, and a change counts as an update on the next pass
+        for (name, (args, new)) in conflicts {
+            let function = &self.functions[&name];
+            let value = match function.get(&args) {
+                // no command is to blame, so point at the function's declaration
+                Some(current) => self.eval_merge(name, function.decl.span, current.clone(), new)?,
+                None => new,
+            };
+            let timestamp = self.timestamp;
+            let function = self.functions.get_mut(&name).unwrap();
+            function.insert(args, value, timestamp);
+        }
+        Ok(new_unions)
+    }
+
+    /// Combines two outputs of a function for the same arguments with its
+    /// merge expression, which may only be left out if they are equal.
+    /// An error is reported at `span`.
+    fn eval_merge(
+        &mut self,
+        name: Symbol,
+        span: Span,
+        old: Value,
+        new: Value,
+    ) -> Result<Value, Error> {
+        match self.functions[&name].decl.merge.clone() {
+            Some(expr) => {
+                let mut ctx = Subst::default();
+                ctx.insert("old".into(), old);
+                ctx.insert("new".into(), new);
+                Ok(self.eval_expr(&ctx, &expr)?)
+            }
+            None if old == new => Ok(old),
+            None => Err(Error::MergeError(name, old, new, span)),
         }
-        new_unions
     }
 
     pub fn declare_sort(&mut self, name: impl Into<Symbol>) -> Result<(), Error> {
@@ -383,26 +604,84 @@ impl EGraph {
         }
     }
 
+    /// Adds an implementation of the primitive `name` for the given input types,
+    /// overloading any implementations it already has for other input types.
+    /// It can be called in rules, actions, and checks like the built-in primitives.
+    /// Returning None means there's no result for those inputs, so a rule
+    /// calling it doesn't match, and an action calling it does nothing.
+    /// Primitives only take and return numbers, strings, and bools.
+    pub fn add_primitive(
+        &mut self,
+        name: impl Into<Symbol>,
+        input: Vec<Type>,
+        output: Type,
+        f: impl Fn(&[Value]) -> Option<Value> + Send + Sync + 'static,
+    ) -> Result<(), Error> {
+        let name = name.into();
+        if let Some(ty) =
+            (input.iter().chain([&output])).find(|ty| matches!(ty, Type::Sort(_) | Type::Unit))
+        {
+            return Err(Error::InvalidPrimitiveType(name, ty.clone()));
+        }
+        let prims = self.primitives.entry(name).or_default();
+        if prims.iter().any(|p| p.input == input) {
+            return Err(Error::PrimitiveAlreadyBound(name, input));
+        }
+        prims.push(Primitive {
+            input,
+            output,
+            f: Arc::new(f),
+        });
+        Ok(())
+    }
+
     pub fn declare_function(&mut self, decl: &FunctionDecl) -> Result<(), Error> {
         for ty in &decl.schema.input {
             if let Type::Sort(sort) = ty {
                 if !self.sorts.contains_key(sort) {
-                    return Err(TypeError::UndefinedSort(*sort).into());
+                    return Err(TypeError::UndefinedSort(*sort, decl.span).into());
                 }
             }
         }
 
         if let Type::Sort(sort) = &decl.schema.output {
             if !self.sorts.contains_key(sort) {
-                return Err(TypeError::UndefinedSort(*sort).into());
+                return Err(TypeError::UndefinedSort(*sort, decl.span).into());
             }
         }
 
+        let output = &decl.schema.output;
+        let check =
+            |types: HashMap<Symbol, Type>, expr: &Expr, reason: &str| -> Result<(), Error> {
+                let actual = self.typecheck_expr(types, expr)?;
+                if &actual == output {
+                    Ok(())
+                } else {
+                    Err(TypeError::Mismatch {
+                        expr: expr.clone(),
+                        expected: output.clone(),
+                        actual,
+                        reason: reason.into(),
+                    }
+                    .into())
+                }
+            };
+        if let Some(merge) = &decl.merge {
+            let types = [
+                ("old".into(), output.clone()),
+                ("new".into(), output.clone()),
+            ];
+            check(types.into_iter().collect(), merge, "merge")?;
+        }
+        if let Some(default) = &decl.default {
+            check(Default::default(), default, "default")?;
+        }
+
         let old = self
             .functions
             .insert(decl.name, Function::new(decl.clone()));
         if old.is_some() {
-            return Err(TypeError::FunctionAlreadyBound(decl.name).into());
+            return Err(TypeError::FunctionAlreadyBound(decl.name, decl.span).into());
         }
 
         Ok(())
@@ -414,61 +693,91 @@ impl EGraph {
         types: Vec<Type>,
         sort: impl Into<Symbol>,
     ) -> Result<(), Error> {
-        let name = name.into();
+        let variant = Variant {
+            span: Span::default(),
+            name: name.into(),
+            types,
+            cost: None,
+            unextractable: false,
+        };
+        self.declare_variant(variant, sort)
+    }
+
+    /// Declares a constructor of `sort` with the span, cost, and
+    /// extractability of a variant of a `datatype`.
+    pub fn declare_variant(
+        &mut self,
+        variant: Variant,
+        sort: impl Into<Symbol>,
+    ) -> Result<(), Error> {
         let sort = sort.into();
         self.declare_function(&FunctionDecl {
-            name,
+            span: variant.span,
+            name: variant.name,
             schema: Schema {
-                input: types,
+                input: variant.types,
                 output: Type::Sort(sort),
             },
             merge: None,
             default: None,
+            cost: variant.cost,
+            unextractable: variant.unextractable,
         })?;
         if let Some(ctors) = self.sorts.get_mut(&sort) {
-            ctors.push(name);
+            ctors.push(variant.name);
         }
         Ok(())
     }
 
     // this must be &mut because it'll call "make_set",
     // but it'd be nice if that didn't have to happen
-    pub fn eval_expr(&mut self, ctx: &Subst, expr: &Expr) -> Result<Value, NotFoundError> {
+    pub fn eval_expr(&mut self, ctx: &Subst, expr: &Expr) -> Result<Value, Error> {
         match expr {
             // TODO should we canonicalize here?
-            Expr::Var(var) => Ok(ctx
+            Expr::Var(span, var) => Ok(ctx
                 .get(var)
                 .or_else(|| self.globals.get(var))
                 .cloned()
-                .unwrap_or_else(|| panic!("Couldn't find variable '{var}'"))),
-            Expr::Lit(lit) => Ok(lit.to_value()),
-            Expr::Call(op, args) => {
-                let values: Vec<Value> = args
+                .ok_or(TypeError::Unbound(*var, *span))?),
+            Expr::Lit(_, lit) => Ok(lit.to_value()),
+            Expr::Call(span, op, args) => {
+                let mut values: Vec<Value> = args
                     .iter()
                     .map(|a| self.eval_expr(ctx, a))
                     .collect::<Result<_, _>>()?;
-                if let Some(function) = self.functions.get_mut(op) {
-                    if let Some(value) = function.nodes.get(&values) {
+                let timestamp = self.timestamp;
+                if let Some(function) = self.functions.get(op) {
+                    if self.unionfind.proofs.is_some() {
+                        if function.decl.default.is_none() && function.decl.schema.output.is_sort()
+                        {
+                            return Ok(self.make_term(*op, values));
+                        }
+                        // terms aren't canonical, but the database is
+                        values = values.into_iter().map(|v| self.bad_find_value(v)).collect();
+                    }
+                    let function = self.functions.get_mut(op).unwrap();
+                    if let Some(value) = function.get(&values) {
                         Ok(value.clone())
                     } else {
                         match (function.decl.default.as_ref(), &function.decl.schema.output) {
                             (None, Type::Unit) => {
-                                function.nodes.insert(values, Value(ValueInner::Unit));
+                                function.insert(values, Value(ValueInner::Unit), timestamp);
                                 Ok(Value(ValueInner::Unit))
                             }
                             (None, Type::Sort(_)) => {
                                 let id = self.unionfind.make_set();
-                                function.nodes.insert(values, Value(ValueInner::Id(id)));
+                                function.insert(values, Value(ValueInner::Id(id)), timestamp);
                                 Ok(Value(ValueInner::Id(id)))
                             }
                             (Some(default), _) => {
                                 let default = default.clone(); // break the borrow
                                 let value = self.eval_expr(ctx, &default)?;
                                 let function = self.functions.get_mut(op).unwrap();
-                                function.nodes.insert(values, value.clone());
+                                function.insert(values, value.clone(), timestamp);
                                 Ok(value)
                             }
-                            _ => panic!("invalid default"),
+                            // there's nothing to make up a value from
+                            _ => Err(NotFoundError(expr.clone()).into()),
                         }
                     }
                 } else if let Some(prims) = self.primitives.get(op) {
@@ -478,54 +787,299 @@ impl EGraph {
                             if res.is_none() {
                                 res = Some(prim.apply(&values));
                             } else {
-                                panic!("multiple implementation matches primitives {op}");
+                                return Err(Error::AmbiguousPrimitive(expr.clone()));
                             }
                         }
                     }
-                    res.ok_or_else(|| NotFoundError(expr.clone()))
+                    // a primitive without a result for these inputs is like a missing tuple
+                    Ok(res.flatten().ok_or_else(|| NotFoundError(expr.clone()))?)
                 } else {
-                    panic!("Couldn't find function/primitive: {op}")
+                    Err(TypeError::Unbound(*op, *span).into())
                 }
             }
         }
     }
 
-    pub fn eval_closed_expr(&mut self, expr: &Expr) -> Result<Value, NotFoundError> {
+    pub fn eval_closed_expr(&mut self, expr: &Expr) -> Result<Value, Error> {
         self.eval_expr(&Default::default(), expr)
     }
 
-    fn query(&self, query: &Query, callback: impl FnMut(&[Value])) {
-        let compiled_query = self.compile_gj_query(&query.atoms);
-        self.run_query(&compiled_query, callback)
+    /// Calls `callback` on every match of `query` that uses
+    /// at least one tuple stamped at or after `timestamp`.
+    fn query(&mut self, query: &Query, timestamp: u32, callback: impl FnMut(&[Value])) {
+        let compiled_query = self.compile_gj_query(query);
+        self.build_indexes(&compiled_query);
+        self.run_query(&compiled_query, timestamp, callback)
+    }
+
+    /// Runs the rules in the default ruleset for at most `limit` iterations,
+    /// stopping early once an iteration makes no updates.
+    pub fn run_rules(&mut self, limit: usize) -> Result<RunReport, Error> {
+        let deadline = self.deadline();
+        self.run_ruleset_unchecked("".into(), limit, deadline)
+    }
+
+    /// Runs the rules in the default ruleset until an iteration makes no updates.
+    pub fn run_until_saturated(&mut self) -> Result<RunReport, Error> {
+        self.run_rules(usize::MAX)
+    }
+
+    /// Runs the rules in the given ruleset for at most `limit` iterations,
+    /// stopping early once an iteration makes no updates.
+    pub fn run_ruleset(&mut self, ruleset: Symbol, limit: usize) -> Result<RunReport, Error> {
+        self.check_ruleset(ruleset)?;
+        let deadline = self.deadline();
+        self.run_ruleset_unchecked(ruleset, limit, deadline)
+    }
+
+    fn check_global_unbound(&self, name: Symbol, span: Span) -> Result<(), Error> {
+        if self.global_types.contains_key(&name) {
+            Err(Error::GlobalAlreadyBound(name, span))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_ruleset(&self, ruleset: Symbol) -> Result<(), Error> {
+        if self.rulesets.contains(&ruleset) {
+            Ok(())
+        } else {
+            Err(Error::RulesetNotFound(ruleset))
+        }
+    }
+
+    /// Sets the limits checked while running the rules.
+    pub fn set_run_config(&mut self, config: RunConfig) {
+        self.run_config = config;
+    }
+
+    pub fn run_config(&self) -> &RunConfig {
+        &self.run_config
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn deadline(&self) -> Option<Instant> {
+        (self.run_config.time_limit).map(|limit| Instant::now() + limit)
+    }
+
+    /// There is no clock on wasm, so runs have no deadline.
+    #[cfg(target_arch = "wasm32")]
+    fn deadline(&self) -> Option<Instant> {
+        if let Some(limit) = self.run_config.time_limit {
+            log::warn!("Ignoring the time limit of {limit:?}, since wasm has no clock");
+        }
+        None
     }
 
-    pub fn run_rules(&mut self, limit: usize) {
-        for _ in 0..limit {
-            self.step_rules();
-            let updates = self.rebuild();
-            log::debug!("Made {updates} updates",);
-            // if updates == 0 {
-            //     log::debug!("Breaking early!");
-            //     break;
-            // }
+    /// Checks the limits of the run config, except for the node limit
+    /// unless `count_tuples`, since counting them visits every function.
+    fn check_limits(&self, deadline: Option<Instant>, count_tuples: bool) -> Option<StopReason> {
+        let config = &self.run_config;
+        if let (Some(limit), true) = (config.node_limit, count_tuples) {
+            if self.functions.values().map(|f| f.len()).sum::<usize>() > limit {
+                return Some(StopReason::NodeLimit(limit));
+            }
+        }
+        if let Some(limit) = config.size_limit {
+            if self.unionfind.len() > limit {
+                return Some(StopReason::SizeLimit(limit));
+            }
+        }
+        if let (Some(limit), Some(deadline)) = (config.time_limit, deadline) {
+            if Instant::now() > deadline {
+                return Some(StopReason::TimeLimit(limit));
+            }
+        }
+        if let Some(cancel) = &config.cancel {
+            if cancel.load(Ordering::Relaxed) {
+                return Some(StopReason::Cancelled);
+            }
         }
+        None
+    }
+
+    fn run_ruleset_unchecked(
+        &mut self,
+        ruleset: Symbol,
+        limit: usize,
+        deadline: Option<Instant>,
+    ) -> Result<RunReport, Error> {
+        let mut report = RunReport::default();
+        // don't count updates from top-level actions against the first iteration
+        self.rebuild()?;
+        // each stratum runs on its own, so the negations in the next one
+        // see everything this one derives
+        let n_strata = (self.rules.values())
+            .filter(|r| r.ruleset == ruleset)
+            .map(|r| r.stratum + 1)
+            .max()
+            .unwrap_or(1);
+        let mut saturated = true;
+        for stratum in 0..n_strata {
+            // the strata share the iterations, so the last ones may not get any
+            let limit = limit - report.iterations;
+            let pass = self.run_stratum(ruleset, stratum, limit, deadline)?;
+            saturated &= pass.saturated;
+            report.add(pass);
+            if report.stop_reason.is_some() {
+                break;
+            }
+        }
+        report.saturated = saturated;
 
         // TODO detect functions
         for (name, r) in &self.functions {
             log::debug!("{name}:");
-            for (args, val) in &r.nodes {
-                log::debug!("  {args:?} = {val}");
+            for (args, out) in &r.nodes {
+                log::debug!("  {args:?} = {}", out.value);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Runs one stratum of a ruleset, like [`EGraph::run_ruleset`].
+    fn run_stratum(
+        &mut self,
+        ruleset: Symbol,
+        stratum: usize,
+        limit: usize,
+        deadline: Option<Instant>,
+    ) -> Result<RunReport, Error> {
+        let mut report = RunReport::default();
+        while report.iterations < limit {
+            if let Some(reason) = self.check_limits(deadline, true) {
+                report.stop_reason = Some(reason);
+                break;
+            }
+            let n_unions = self.unionfind.n_unions();
+            let stopped = self.step_rules(ruleset, stratum, deadline)?;
+            let updates = self.unionfind.n_unions() - n_unions + self.rebuild()?;
+            log::debug!("Made {updates} updates");
+            report.iterations += 1;
+            report.updates += updates;
+            if let Some(reason) = stopped {
+                log::debug!("Stopped by {reason} after {} iterations", report.iterations);
+                report.stop_reason = Some(reason);
+                break;
+            }
+            if updates == 0 && self.scheduler_can_stop(ruleset, stratum) {
+                log::debug!("Saturated after {} iterations", report.iterations);
+                report.saturated = true;
+                break;
             }
         }
+        Ok(report)
+    }
+
+    fn scheduler_can_stop(&mut self, ruleset: Symbol, stratum: usize) -> bool {
+        let rules: Vec<Symbol> = (self.rules.iter())
+            .filter(|(_, r)| r.ruleset == ruleset && r.stratum == stratum)
+            .map(|(name, _)| *name)
+            .collect();
+        self.scheduler.can_stop(self.timestamp as usize, &rules)
     }
 
-    fn step_rules(&mut self) {
-        let searched: Vec<_> = self
+    /// Sets the scheduler that decides which rules are searched and applied.
+    pub fn set_scheduler(&mut self, scheduler: impl Scheduler + 'static) {
+        self.scheduler = Box::new(scheduler);
+    }
+
+    /// Runs a schedule, returning the combined report of every run in it.
+    pub fn run_schedule(&mut self, schedule: &Schedule) -> Result<RunReport, Error> {
+        schedule.rulesets(&mut |ruleset| self.check_ruleset(ruleset))?;
+        let deadline = self.deadline();
+        self.run_schedule_unchecked(schedule, deadline)
+    }
+
+    fn run_schedule_unchecked(
+        &mut self,
+        schedule: &Schedule,
+        deadline: Option<Instant>,
+    ) -> Result<RunReport, Error> {
+        let mut report = RunReport::default();
+        match schedule {
+            Schedule::Run { ruleset, limit } => {
+                let limit = limit.unwrap_or(usize::MAX);
+                report = self.run_ruleset_unchecked(*ruleset, limit, deadline)?;
+            }
+            Schedule::Seq(schedules) => {
+                for schedule in schedules {
+                    report.add(self.run_schedule_unchecked(schedule, deadline)?);
+                    if report.stop_reason.is_some() {
+                        break;
+                    }
+                }
+            }
+            Schedule::Repeat(n, schedule) => {
+                for _ in 0..*n {
+                    let pass = self.run_schedule_unchecked(schedule, deadline)?;
+                    let stop = (pass.updates == 0 && pass.saturated) || pass.stop_reason.is_some();
+                    report.add(pass);
+                    if stop {
+                        break;
+                    }
+                }
+            }
+            Schedule::Saturate(schedule) => loop {
+                let pass = self.run_schedule_unchecked(schedule, deadline)?;
+                let stop = (pass.updates == 0 && pass.saturated) || pass.stop_reason.is_some();
+                report.add(pass);
+                if stop {
+                    break;
+                }
+            },
+        }
+        Ok(report)
+    }
+
+    /// Searches and then applies the rules in the ruleset once, returning
+    /// early if a limit is hit or an action fails. Rules whose matches were
+    /// not all applied will find them again next time.
+    fn step_rules(
+        &mut self,
+        ruleset: Symbol,
+        stratum: usize,
+        deadline: Option<Instant>,
+    ) -> Result<Option<StopReason>, Error> {
+        let in_stratum = |r: &Rule| r.ruleset == ruleset && r.stratum == stratum;
+        let mut rules = std::mem::take(&mut self.rules);
+        for (name, rule) in rules.iter_mut().filter(|(_, r)| in_stratum(r)) {
+            if rule.plan.as_ref().is_none_or(|p| self.plan_is_stale(p)) {
+                let plan = self.compile_gj_query(&rule.query);
+                log::debug!("Planned {name}: {plan}");
+                self.build_indexes(&plan);
+                rule.plan = Some(plan);
+            }
+        }
+        self.rules = rules;
+
+        // every iteration gets its own timestamp, so the scheduler counts in those
+        let iteration = self.timestamp as usize;
+        let mut scheduler = std::mem::replace(&mut self.scheduler, Box::new(SimpleScheduler));
+        let mut stopped = None;
+        // for each rule that was searched: its matches, whether to apply them,
+        // and how long the search took
+        let searched: Vec<Option<(Vec<Subst>, bool, Duration)>> = self
             .rules
-            .values()
-            .map(|rule| {
+            .iter()
+            .filter(|(_, r)| in_stratum(r))
+            .map(|(name, rule)| {
+                if stopped.is_some() {
+                    return None;
+                }
+                stopped = self.check_limits(deadline, true);
+                if stopped.is_some() || !scheduler.should_search(iteration, *name) {
+                    return None;
+                }
                 let mut substs = Vec::<Subst>::new();
-                self.query(&rule.query, |values| {
+                let plan = rule.plan.as_ref().unwrap();
+                // an aggregate can change without any new tuples in the rest of the query
+                let timestamp = match rule.query.aggregates.is_empty() {
+                    true => rule.search_timestamp,
+                    false => 0,
+                };
+                let stopwatch = Stopwatch::start();
+                self.run_query(plan, timestamp, |values| {
                     let get = |a: &AtomTerm| -> Value {
                         match a {
                             AtomTerm::Var(i) => values[*i].clone(),
@@ -540,30 +1094,141 @@ impl EGraph {
                             .collect(),
                     )
                 });
-                substs
+                let search_time = stopwatch.elapsed();
+                let apply = scheduler.should_apply(iteration, *name, substs.len());
+                Some((substs, apply, search_time))
             })
             .collect();
+        self.scheduler = scheduler;
+
+        // everything that exists now has been searched,
+        // so anything added from here on is new to every rule
+        self.timestamp += 1;
+        let mut rules = std::mem::take(&mut self.rules);
+        let mut error = None;
+        let rules_in_stratum = rules.iter_mut().filter(|(_, r)| in_stratum(r));
+        for ((name, rule), searched) in rules_in_stratum.zip(searched) {
+            let name = *name;
+            let Some((substs, apply, search_time)) = searched else {
+                continue;
+            };
+            rule.stats.searches += 1;
+            rule.stats.matches += substs.len();
+            rule.stats.search_time += search_time;
+            // skipped matches are found again the next time the rule is searched,
+            // and if a limit was hit while searching, nothing gets applied
+            if !apply || stopped.is_some() {
+                continue;
+            }
 
-        let rules = std::mem::take(&mut self.rules);
-        for (rule, substs) in rules.values().zip(searched) {
+            let stopwatch = Stopwatch::start();
             for subst in substs {
-                // we ignore the result here because rule applications are best effort
-                let _result: Result<_, _> = self.eval_actions(Some(subst), &rule.head);
+                if self.unionfind.proofs.is_some() {
+                    self.reason = Justification::Rule(name, subst.clone());
+                }
+                let n_changes = self.n_changes();
+                match self.eval_actions(Some(subst), &rule.head) {
+                    // rule applications are best effort, so a lookup that
+                    // finds nothing just skips this match
+                    Ok(()) | Err(Error::NotFoundError(_)) => {}
+                    Err(err) => {
+                        error = Some(err);
+                        break;
+                    }
+                }
+                if self.n_changes() != n_changes {
+                    rule.stats.changes += 1;
+                }
+                stopped = self.check_limits(deadline, false);
+                if stopped.is_some() {
+                    break;
+                }
+            }
+            rule.stats.apply_time += stopwatch.elapsed();
+            if error.is_some() {
+                break;
+            }
+            if stopped.is_none() {
+                rule.search_timestamp = self.timestamp;
+                stopped = self.check_limits(deadline, true);
             }
         }
         self.rules = rules;
+        match error {
+            Some(err) => Err(err),
+            None => Ok(stopped),
+        }
+    }
+
+    /// The number of unions and table updates made since the last rebuild.
+    fn n_changes(&self) -> usize {
+        self.unionfind.n_unions() + self.functions.values().map(|f| f.updates).sum::<usize>()
+    }
+
+    /// The statistics of every rule, accumulated over all runs.
+    pub fn rule_stats(&self) -> impl Iterator<Item = (Symbol, &RuleStats)> {
+        self.rules.iter().map(|(name, rule)| (*name, &rule.stats))
+    }
+
+    /// A table of [`EGraph::rule_stats`], most expensive rule first.
+    pub fn rule_stats_table(&self) -> String {
+        let mut stats: Vec<_> = self.rule_stats().collect();
+        stats.sort_by_key(|(_, s)| std::cmp::Reverse(s.search_time + s.apply_time));
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let mut table = format!(
+            "{:>8} {:>8} {:>8} {:>11} {:>11}  rule",
+            "searches", "matches", "changes", "search (ms)", "apply (ms)"
+        );
+        for (name, s) in stats {
+            table += &format!(
+                "\n{:>8} {:>8} {:>8} {:>11.3} {:>11.3}  {name}",
+                s.searches,
+                s.matches,
+                s.changes,
+                ms(s.search_time),
+                ms(s.apply_time),
+            );
+        }
+        table
+    }
+
+    pub fn declare_ruleset(&mut self, name: impl Into<Symbol>) -> Result<(), Error> {
+        let name = name.into();
+        if self.rulesets.insert(name) {
+            Ok(())
+        } else {
+            Err(Error::RulesetAlreadyBound(name))
+        }
     }
 
     fn add_rule_with_name(&mut self, name: String, rule: ast::Rule) -> Result<Symbol, Error> {
-        let name = Symbol::from(name);
+        // the same rule can be in more than one ruleset
+        let name = match rule.ruleset.as_str() {
+            "" => Symbol::from(name),
+            ruleset => Symbol::from(format!("{name} :ruleset {ruleset}")),
+        };
+        self.check_ruleset(rule.ruleset)?;
+        let query = self.compile_query(rule.body)?;
+        let types = self.typecheck_actions(query.types.clone(), &rule.head)?;
+        let deps = self.dependencies(&query, &rule.head, &types);
         let compiled_rule = Rule {
-            query: self.compile_query(rule.body)?,
+            ruleset: rule.ruleset,
+            query,
             head: rule.head,
+            search_timestamp: 0,
+            plan: None,
+            stats: Default::default(),
+            deps,
+            stratum: 0,
         };
         match self.rules.entry(name) {
-            Entry::Occupied(_) => panic!("Rule '{name}' was already present"),
+            Entry::Occupied(_) => return Err(Error::RuleAlreadyBound(name, rule.span)),
             Entry::Vacant(e) => e.insert(compiled_rule),
         };
+        if let Err(err) = self.stratify(rule.ruleset) {
+            self.rules.remove(&name);
+            return Err(err);
+        }
         Ok(name)
     }
 
@@ -579,35 +1244,29 @@ impl EGraph {
     pub fn add_rewrite(&mut self, rewrite: ast::Rewrite) -> Result<Symbol, Error> {
         let name = format!("{} -> {}", rewrite.lhs, rewrite.rhs);
         let var = Symbol::from("__rewrite_var");
+        let span = rewrite.span;
+        // with proofs, union through the lhs term, which finds the same value
+        // as `var`, but is what a proof should say the rule rewrote
+        let from = if self.proofs_enabled() {
+            rewrite.lhs.clone()
+        } else {
+            Expr::Var(span, var)
+        };
         let rule = ast::Rule {
-            body: vec![Fact::Eq(vec![Expr::Var(var), rewrite.lhs])],
-            head: vec![Action::Union(Expr::Var(var), rewrite.rhs)],
+            span,
+            body: vec![Fact::Eq(span, vec![Expr::Var(span, var), rewrite.lhs])],
+            head: vec![Action::Union(span, from, rewrite.rhs)],
+            ruleset: rewrite.ruleset,
         };
         self.add_rule_with_name(name, rule)
     }
 
-    fn for_each_canonicalized(&self, name: Symbol, mut cb: impl FnMut(&[Value])) {
-        let mut ids = vec![];
-        let f = self
-            .functions
-            .get(&name)
-            .unwrap_or_else(|| panic!("No function {name}"));
-        for (children, value) in &f.nodes {
-            ids.clear();
-            // FIXME canonicalize, do we need to with rebuilding?
-            // ids.extend(children.iter().map(|id| self.find(value)));
-            ids.extend(children.iter().cloned());
-            ids.push(value.clone());
-            cb(&ids);
-        }
-    }
-
     fn run_command(&mut self, command: Command, should_run: bool) -> Result<String, Error> {
         Ok(match command {
             Command::Datatype { name, variants } => {
                 self.declare_sort(name)?;
                 for variant in variants {
-                    self.declare_constructor(variant.name, variant.types, name)?;
+                    self.declare_variant(variant, name)?;
                 }
                 format!("Declared datatype {name}.")
             }
@@ -623,26 +1282,113 @@ impl EGraph {
                 let name = self.add_rewrite(rewrite)?;
                 format!("Declared rw {name}.")
             }
-            Command::Run(limit) => {
+            Command::Ruleset(name) => {
+                self.declare_ruleset(name)?;
+                format!("Declared ruleset {name}.")
+            }
+            Command::Run(schedule) => {
                 if should_run {
-                    self.run_rules(limit);
-                    format!("Ran {limit}.")
+                    self.run_schedule(&schedule)?.to_string()
                 } else {
                     log::info!("Skipping running!");
-                    format!("Skipped run {limit}.")
+                    "Skipped run.".into()
                 }
             }
-            Command::Extract(e) => {
-                if should_run {
-                    // TODO typecheck
+            Command::SetScheduler {
+                name,
+                match_limit,
+                ban_length,
+            } => {
+                match name.as_str() {
+                    "simple" => self.set_scheduler(SimpleScheduler),
+                    "backoff" => {
+                        let mut scheduler = BackoffScheduler::default();
+                        if let Some(match_limit) = match_limit {
+                            scheduler = scheduler.with_match_limit(match_limit);
+                        }
+                        if let Some(ban_length) = ban_length {
+                            scheduler = scheduler.with_ban_length(ban_length);
+                        }
+                        self.set_scheduler(scheduler)
+                    }
+                    _ => return Err(Error::SchedulerNotFound(name)),
+                }
+                format!("Set scheduler to {name}.")
+            }
+            Command::SetLimits {
+                node_limit,
+                size_limit,
+                time_limit,
+            } => {
+                let cancel = self.run_config.cancel.take();
+                self.set_run_config(RunConfig {
+                    node_limit,
+                    size_limit,
+                    time_limit,
+                    cancel,
+                });
+                "Set limits.".into()
+            }
+            Command::EnableProofs => {
+                self.enable_proofs()?;
+                "Enabled proofs.".into()
+            }
+            Command::ExplainEq(a, b) => self.explain_equivalence(&a, &b)?.to_string(),
+            Command::PrintStats => self.rule_stats_table(),
+            Command::Extract {
+                span,
+                expr: e,
+                mode,
+                only,
+                with_functions,
+            } => {
+                let ty = self.typecheck_expr(Default::default(), &e)?;
+                for name in only.iter().flatten() {
+                    if !self.functions.contains_key(name) {
+                        return Err(TypeError::Unbound(*name, span).into());
+                    }
+                }
+                if !should_run {
+                    "Skipping extraction.".into()
+                } else if !ty.is_sort() {
+                    // numbers, strings, bools, and relations are their own best terms,
+                    // looked up so that extracting doesn't add to the database
+                    self.rebuild()?;
+                    let global = |var: Symbol| self.bad_find_value(self.globals[&var].clone());
+                    let value =
+                        (self.lookup_expr(&e, &global)).ok_or_else(|| NotFoundError(e.clone()))?;
+                    format!("Extracted {}", value.to_literal())
+                } else {
                     let value = self.eval_closed_expr(&e)?;
-                    self.rebuild();
-                    let id = Id::from(value);
+                    self.rebuild()?;
+                    let id = self.find(Id::from(value));
                     log::info!("Extracting {e} at {id}");
-                    let (cost, expr) = self.extract(id);
-                    format!("Extracted with cost {cost}: {expr}")
-                } else {
-                    "Skipping extraction.".into()
+                    let extractor = Extractor::new(self, only.as_deref());
+                    let mut msg = match mode {
+                        ExtractMode::Tree => {
+                            let (cost, expr) = extractor.find_best(id)?;
+                            format!("Extracted with cost {cost}: {expr}")
+                        }
+                        ExtractMode::Dag { time_limit } => {
+                            let time_limit = time_limit.unwrap_or(DEFAULT_DAG_TIME_LIMIT);
+                            let (cost, expr) = extractor.find_best_dag(id, time_limit)?;
+                            format!("Extracted DAG with cost {cost}: {expr}")
+                        }
+                        ExtractMode::Variants(n) => {
+                            let variants = extractor.find_variants(id, n)?;
+                            let mut msg = format!("Extracted {} variants:", variants.len());
+                            for (cost, expr) in variants {
+                                msg += &format!("\n  cost {cost}: {expr}");
+                            }
+                            msg
+                        }
+                    };
+                    if with_functions {
+                        for fact in extractor.function_values(id) {
+                            msg += &format!("\n  {fact}");
+                        }
+                    }
+                    msg
                 }
             }
             Command::Check(fact) => {
@@ -654,7 +1400,15 @@ impl EGraph {
                 }
             }
             Command::Action(action) => {
+                if let Action::Define(span, name, _) = &action {
+                    self.check_global_unbound(*name, *span)?;
+                }
+                let types =
+                    self.typecheck_actions(Default::default(), std::slice::from_ref(&action))?;
+                // a top-level define makes a global
+                self.global_types.extend(types);
                 if should_run {
+                    self.reason = Justification::Action(action.to_string());
                     self.eval_actions(None, std::slice::from_ref(&action))?;
                     format!("Run {action}.")
                 } else {
@@ -662,10 +1416,12 @@ impl EGraph {
                 }
             }
             Command::Define(name, expr) => {
+                self.check_global_unbound(name, expr.span())?;
+                let ty = self.typecheck_expr(Default::default(), &expr)?;
+                self.global_types.insert(name, ty);
                 if should_run {
                     let value = self.eval_closed_expr(&expr)?;
-                    let old = self.globals.insert(name, value);
-                    assert!(old.is_none());
+                    self.globals.insert(name, value);
                     format!("Defined {name}")
                 } else {
                     format!("Skipping define {name}")
@@ -675,17 +1431,16 @@ impl EGraph {
                 self.clear_rules();
                 "Clearing rules.".into()
             }
+            Command::Explain(q) => self.explain_query(q)?.to_string(),
             Command::Query(q) => {
                 let qsexp = sexp::Sexp::List(
                     q.iter()
                         .map(|fact| sexp::parse(&fact.to_string()).unwrap())
                         .collect(),
                 );
-                let qcomp = self
-                    .compile_query(q)
-                    .unwrap_or_else(|_| panic!("Could not compile query"));
+                let qcomp = self.compile_query(q)?;
                 let mut res = vec![];
-                self.query(&qcomp, |v| {
+                self.query(&qcomp, 0, |v| {
                     res.push(sexp::Sexp::List(
                         v.iter()
                             .map(|val| sexp::Sexp::Atom(sexp::Atom::S(format!("{}", val))))
@@ -743,9 +1498,87 @@ pub enum Error {
     #[error("{}", ListDisplay(.0, "\n"))]
     TypeErrors(Vec<TypeError>),
     #[error("Check failed: {0} != {1}")]
-    CheckError(Value, Value),
+    CheckError(Value, Value, Span),
+    #[error("Check failed: {0} holds")]
+    NegatedCheckError(Fact),
     #[error("Sort {0} already declared.")]
     SortAlreadyBound(Symbol),
+    #[error("Ruleset {0} already declared.")]
+    RulesetAlreadyBound(Symbol),
+    #[error("Ruleset {0} not declared.")]
+    RulesetNotFound(Symbol),
+    #[error("Proofs have to be enabled before anything is added to the database.")]
+    ProofsEnabledTooLate,
+    #[error("Proofs are not enabled, use (enable-proofs) first.")]
+    ProofsDisabled,
+    #[error("{0} and {1} are not equal.")]
+    NotEqual(Expr, Expr),
+    #[error("{0} and {1} are equal, but not for a reason that can be explained.")]
+    Unexplainable(Expr, Expr),
+    #[error("Unknown scheduler {0}, expected simple or backoff.")]
+    SchedulerNotFound(Symbol),
+    #[error("Function {0} has no merge expression, but got both {1} and {2}.")]
+    MergeError(Symbol, Value, Value, Span),
+    #[error("Rule {0} already declared.")]
+    RuleAlreadyBound(Symbol, Span),
+    #[error("Global {0} already defined.")]
+    GlobalAlreadyBound(Symbol, Span),
+    #[error("Primitive {0} already has an implementation taking ({}).", ListDisplay(.1, " "))]
+    PrimitiveAlreadyBound(Symbol, Vec<Type>),
+    #[error("Primitive {0} can't take or return {1}, only numbers, strings, and bools.")]
+    InvalidPrimitiveType(Symbol, Type),
+    #[error("E-class {0} has no terms made only of extractable constructors.")]
+    NotExtractable(Id),
+    #[error("More than one implementation of the primitive matches {0}.")]
+    AmbiguousPrimitive(Expr),
+    #[error("Only calls and equalities can be checked, not {0}.")]
+    InvalidCheck(Expr),
+    #[error("Rule {0} negates or aggregates over {1}, which depends on what the rule derives, so the rules can't be stratified.")]
+    NotStratifiable(Symbol, Symbol),
+    #[error("Panic: {0}")]
+    Panic(String),
+}
+
+impl Error {
+    /// Where the error is in the source, if it's known.
+    pub fn span(&self) -> Option<Span> {
+        use lalrpop_util::ParseError::*;
+        match self {
+            Error::ParseError(InvalidToken { location } | UnrecognizedEOF { location, .. }) => {
+                Some(Span::new(*location, location + 1))
+            }
+            Error::ParseError(
+                UnrecognizedToken {
+                    token: (start, _, end),
+                    ..
+                }
+                | ExtraToken {
+                    token: (start, _, end),
+                },
+            ) => Some(Span::new(*start, *end)),
+            Error::NotFoundError(NotFoundError(expr))
+            | Error::NotEqual(expr, _)
+            | Error::Unexplainable(expr, _)
+            | Error::AmbiguousPrimitive(expr)
+            | Error::InvalidCheck(expr) => Some(expr.span()).filter(|span| !span.is_empty()),
+            Error::NegatedCheckError(fact) => Some(fact.span()).filter(|span| !span.is_empty()),
+            Error::CheckError(.., span)
+            | Error::MergeError(.., span)
+            | Error::RuleAlreadyBound(_, span)
+            | Error::GlobalAlreadyBound(_, span) => Some(*span).filter(|span| !span.is_empty()),
+            Error::TypeError(error) => error.span(),
+            Error::TypeErrors(errors) => errors.iter().find_map(|e| e.span()),
+            _ => None,
+        }
+    }
+
+    /// Shows the error along with where it is in `source`, which was read from `path`.
+    pub fn render(&self, path: &str, source: &str) -> String {
+        match self.span() {
+            Some(span) => format!("error: {self}\n{}", span.render(path, source)),
+            None => format!("error: {self}"),
+        }
+    }
 }
 
 pub type Pattern = Expr;
@@ -754,33 +1587,50 @@ pub type Pattern = Expr;
 pub struct Query {
     bindings: HashMap<Symbol, AtomTerm>,
     atoms: Vec<Atom>,
+    /// Calls to primitives, which filter the matches of the atoms
+    /// or compute variables from them.
+    prims: Vec<PrimAtom>,
+    /// Facts that must not hold for a match.
+    negations: Vec<Fact>,
+    aggregates: Vec<Aggregate>,
+    /// The types of the variables, if the query was typechecked.
+    types: HashMap<Symbol, Type>,
 }
 
 impl Query {
-    pub fn from_facts(facts: Vec<Fact>) -> Self {
+    pub fn from_facts(facts: Vec<Fact>) -> Result<Self, Error> {
         #[derive(PartialEq, Eq, Hash, Clone, Debug)]
         enum VarOrValue {
             Var(Symbol),
             Value(Value),
         }
 
+        let (negations, facts): (Vec<Fact>, Vec<Fact>) =
+            facts.into_iter().partition(|f| matches!(f, Fact::Not(..)));
         let mut aux_counter = 0;
         let mut uf = SparseUnionFind::<VarOrValue, ()>::default();
         let mut pre_atoms: Vec<(Symbol, Vec<VarOrValue>)> = vec![];
+        // where each literal first appears, to report errors at
+        let mut lit_spans = HashMap::<Value, Span>::default();
 
         for (i, fact) in facts.into_iter().enumerate() {
             let group_var = VarOrValue::Var(Symbol::from(format!("__group_{i}")));
             uf.insert(group_var.clone(), ());
             let group: Vec<Expr> = match fact {
-                Fact::Eq(exprs) => exprs,
+                Fact::Eq(_, exprs) => exprs,
                 Fact::Fact(expr) => vec![expr],
+                Fact::Not(..) => unreachable!(),
             };
             for expr in group {
                 let vv = expr.fold(&mut |expr, mut child_pre_atoms| -> VarOrValue {
                     let vv = match expr {
-                        Expr::Lit(lit) => VarOrValue::Value(lit.to_value()),
-                        Expr::Var(var) => VarOrValue::Var(*var),
-                        Expr::Call(op, _) => {
+                        Expr::Lit(span, lit) => {
+                            let value = lit.to_value();
+                            lit_spans.entry(value.clone()).or_insert(*span);
+                            VarOrValue::Value(value)
+                        }
+                        Expr::Var(_, var) => VarOrValue::Var(*var),
+                        Expr::Call(_, op, _) => {
                             let aux = VarOrValue::Var(format!("_aux_{}", aux_counter).into());
                             aux_counter += 1;
                             child_pre_atoms.push(aux.clone());
@@ -808,7 +1658,13 @@ impl Query {
                 .collect();
 
             if values.len() > 1 {
-                panic!("too many values")
+                let literals = values.iter().map(|v| v.to_literal()).collect();
+                // the literal that appears last is the one that conflicts
+                let span = (values.iter())
+                    .map(|v| lit_spans[v])
+                    .max_by_key(|span| span.start)
+                    .unwrap();
+                return Err(TypeError::TooManyLiterals(literals, span).into());
             }
 
             let atom_term = if let Some(value) = values.pop() {
@@ -839,6 +1695,13 @@ impl Query {
             .collect();
 
         log::debug!("atoms: {:?}", atoms);
-        Self { bindings, atoms }
+        Ok(Self {
+            bindings,
+            atoms,
+            prims: vec![],
+            negations,
+            aggregates: vec![],
+            types: Default::default(),
+        })
     }
 }
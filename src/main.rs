@@ -10,10 +10,12 @@ fn main() {
 
     let args: Vec<String> = std::env::args().collect();
     if args.len() <= 1 {
-        eprintln!("Pass in some files as arguments");
+        eprintln!("Pass in some files as arguments, and --stats to print rule statistics");
         std::process::exit(1)
     }
 
+    let print_stats = args[1..].iter().any(|arg| arg == "--stats");
+
     for arg in &args[1..] {
         if arg.ends_with(".egg") {
             let s = std::fs::read_to_string(arg)
@@ -24,9 +26,12 @@ fn main() {
                     for msg in msgs {
                         println!("  {}", msg);
                     }
+                    if print_stats {
+                        println!("{}", egraph.rule_stats_table());
+                    }
                 }
                 Err(err) => {
-                    log::error!("{}", err);
+                    eprintln!("{}", err.render(arg, &s));
                     std::process::exit(1)
                 }
             }
@@ -0,0 +1,288 @@
+use std::fmt::Display;
+
+use crate::*;
+
+/// Why two ids were unioned.
+#[derive(Clone, Debug)]
+pub(crate) enum Justification {
+    /// A rule was applied to a match.
+    Rule(Symbol, Subst),
+    /// A top-level action, or a union through the API.
+    Action(String),
+    /// Both ids are terms that call the same function with equal arguments.
+    Congruence,
+}
+
+/// The proof forest: a spanning tree of each e-class whose edges are the
+/// unions that built it, along with the term that every id stands for.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ProofForest {
+    /// For each id, the next id towards the root of its tree.
+    parents: Vec<Option<(Id, Justification)>>,
+    /// For each id, the function call it was made for, if any.
+    terms: Vec<Option<(Symbol, Vec<Value>)>>,
+    /// Looks up terms by their (uncanonical) arguments.
+    memo: HashMap<(Symbol, Vec<Value>), Id>,
+}
+
+impl ProofForest {
+    pub fn add_id(&mut self) {
+        self.parents.push(None);
+        self.terms.push(None);
+    }
+
+    /// Adds an edge between ids that were in different trees.
+    pub fn add_edge(&mut self, a: Id, b: Id, justification: Justification) {
+        self.reroot(a);
+        self.parents[usize::from(a)] = Some((b, justification));
+    }
+
+    /// Turns the edges around so that `id` becomes the root of its tree.
+    fn reroot(&mut self, id: Id) {
+        let mut current = id;
+        let mut new_parent = None;
+        while let Some((next, justification)) =
+            std::mem::replace(&mut self.parents[usize::from(current)], new_parent)
+        {
+            new_parent = Some((current, justification));
+            current = next;
+        }
+    }
+
+    fn ancestors(&self, mut id: Id) -> Vec<Id> {
+        let mut ancestors = vec![id];
+        while let Some((parent, _)) = &self.parents[usize::from(id)] {
+            id = *parent;
+            ancestors.push(id);
+        }
+        ancestors
+    }
+
+    /// The edges on the path from `a` to `b`, or None if they aren't in the same tree.
+    fn path(&self, a: Id, b: Id) -> Option<Vec<(Id, Id, &Justification)>> {
+        let mut up = self.ancestors(a);
+        let mut down = self.ancestors(b);
+        if up.last() != down.last() {
+            return None;
+        }
+        // drop everything above the lowest common ancestor
+        while up.len() > 1 && down.len() > 1 && up[up.len() - 2] == down[down.len() - 2] {
+            up.pop();
+            down.pop();
+        }
+        let edge = |id: &Id| {
+            let (parent, justification) = self.parents[usize::from(*id)].as_ref().unwrap();
+            (*id, *parent, justification)
+        };
+        let mut path: Vec<_> = up[..up.len() - 1].iter().map(edge).collect();
+        for (from, to, justification) in down[..down.len() - 1].iter().rev().map(edge) {
+            path.push((to, from, justification));
+        }
+        Some(path)
+    }
+
+    pub fn lookup(&self, op: Symbol, args: Vec<Value>) -> Option<Id> {
+        self.memo.get(&(op, args)).copied()
+    }
+
+    pub fn add_term(&mut self, id: Id, op: Symbol, args: Vec<Value>) {
+        self.terms[usize::from(id)] = Some((op, args.clone()));
+        self.memo.insert((op, args), id);
+    }
+
+    /// The term a value stands for.
+    pub fn term(&self, value: &Value) -> Expr {
+        match &value.0 {
+            ValueInner::Id(id) => match &self.terms[usize::from(*id)] {
+                Some((op, args)) => Expr::call(*op, args.iter().map(|a| self.term(a))),
+                None => Expr::var(id.to_string()),
+            },
+            _ => Expr::lit(value.to_literal()),
+        }
+    }
+
+    /// Explains why `a` and `b` are equal, or returns None if some union
+    /// between them wasn't recorded.
+    pub fn explain(&self, a: &Value, b: &Value) -> Option<Explanation> {
+        let mut steps = vec![];
+        if a != b {
+            for (from, to, justification) in self.path(Id::from(a.clone()), Id::from(b.clone()))? {
+                let reason = match justification {
+                    Justification::Rule(name, subst) => {
+                        let mut subst: Vec<(Symbol, Expr)> = (subst.iter())
+                            // leave out the variables made up by the query compiler
+                            .filter(|(var, _)| !var.as_str().starts_with('_'))
+                            .map(|(var, value)| (*var, self.term(value)))
+                            .collect();
+                        subst.sort_by_key(|(var, _)| var.as_str());
+                        ProofReason::Rule { name: *name, subst }
+                    }
+                    Justification::Action(action) => ProofReason::Action(action.clone()),
+                    Justification::Congruence => {
+                        let args = |id: Id| match &self.terms[usize::from(id)] {
+                            Some((_, args)) => args.as_slice(),
+                            None => &[],
+                        };
+                        ProofReason::Congruence(
+                            (args(from).iter().zip(args(to)))
+                                .filter(|(a, b)| a != b)
+                                .map(|(a, b)| self.explain(a, b))
+                                .collect::<Option<_>>()?,
+                        )
+                    }
+                };
+                steps.push(ProofStep {
+                    term: self.term(&to.into()),
+                    reason,
+                });
+            }
+        }
+        Some(Explanation {
+            start: self.term(a),
+            steps,
+        })
+    }
+}
+
+/// A chain of equalities from one term to another,
+/// made by [`EGraph::explain_equivalence`].
+#[derive(Clone, Debug)]
+pub struct Explanation {
+    pub start: Expr,
+    pub steps: Vec<ProofStep>,
+}
+
+/// The previous term in an [`Explanation`] is equal to `term` because of `reason`.
+#[derive(Clone, Debug)]
+pub struct ProofStep {
+    pub term: Expr,
+    pub reason: ProofReason,
+}
+
+#[derive(Clone, Debug)]
+pub enum ProofReason {
+    /// A rule matched with these variables and unioned the terms.
+    Rule {
+        name: Symbol,
+        subst: Vec<(Symbol, Expr)>,
+    },
+    /// A top-level action unioned the terms.
+    Action(String),
+    /// The terms call the same function on equal arguments,
+    /// with a proof for each argument that differs.
+    Congruence(Vec<Explanation>),
+}
+
+impl Explanation {
+    fn fmt_indented(&self, f: &mut std::fmt::Formatter<'_>, indent: usize) -> std::fmt::Result {
+        write!(f, "{:indent$}{}", "", self.start)?;
+        for step in &self.steps {
+            write!(f, "\n{:indent$}= {}  ; by ", "", step.term)?;
+            match &step.reason {
+                ProofReason::Rule { name, subst } => {
+                    write!(f, "rule {name}")?;
+                    for (i, (var, term)) in subst.iter().enumerate() {
+                        let sep = if i == 0 { " with" } else { "," };
+                        write!(f, "{sep} {var} = {term}")?;
+                    }
+                }
+                ProofReason::Action(action) => write!(f, "{action}")?,
+                ProofReason::Congruence(args) => {
+                    write!(f, "congruence")?;
+                    for arg in args {
+                        writeln!(f)?;
+                        arg.fmt_indented(f, indent + 4)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Explanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+impl EGraph {
+    /// Records a justification for every union from now on, so that
+    /// [`EGraph::explain_equivalence`] can explain why terms are equal.
+    /// This has to happen before anything is added to the database.
+    pub fn enable_proofs(&mut self) -> Result<(), Error> {
+        if self.unionfind.len() > 0 || self.functions.values().any(|f| !f.is_empty()) {
+            return Err(Error::ProofsEnabledTooLate);
+        }
+        self.unionfind.proofs = Some(Default::default());
+        Ok(())
+    }
+
+    pub fn proofs_enabled(&self) -> bool {
+        self.unionfind.proofs.is_some()
+    }
+
+    /// Gets the id of the term `op(args)`, making it if it's new. With proofs
+    /// enabled, every term gets its own id, which is unioned with the output
+    /// of any call of `op` that has equal arguments.
+    pub(crate) fn make_term(&mut self, op: Symbol, args: Vec<Value>) -> Value {
+        let proofs = self.unionfind.proofs.as_mut().unwrap();
+        if let Some(id) = proofs.lookup(op, args.clone()) {
+            return id.into();
+        }
+        let id = self.unionfind.make_set();
+        let proofs = self.unionfind.proofs.as_mut().unwrap();
+        proofs.add_term(id, op, args.clone());
+
+        let canonical: Vec<Value> = args.into_iter().map(|a| self.bad_find_value(a)).collect();
+        let timestamp = self.timestamp;
+        let function = self.functions.get_mut(&op).unwrap();
+        if let Some(term) = function.get_term(&canonical) {
+            // the same call with other arguments is already in the database
+            self.unionfind
+                .union_fresh(term, id, || Justification::Congruence);
+        } else {
+            function.insert_term(canonical, id, timestamp);
+        }
+        id.into()
+    }
+
+    /// Gets the id of a term without adding anything to the database.
+    fn lookup_term(&self, expr: &Expr) -> Result<Value, NotFoundError> {
+        let proofs = self.unionfind.proofs.as_ref().unwrap();
+        match expr {
+            Expr::Lit(_, lit) => Ok(lit.to_value()),
+            Expr::Var(_, var) => {
+                (self.globals.get(var).cloned()).ok_or_else(|| NotFoundError(expr.clone()))
+            }
+            Expr::Call(_, op, args) => {
+                let args = (args.iter())
+                    .map(|a| self.lookup_term(a))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let value = if self.functions.contains_key(op) {
+                    proofs.lookup(*op, args).map(Value::from)
+                } else {
+                    // primitives don't make terms, so we can just call them
+                    let mut prims = self.primitives.get(op).into_iter().flatten();
+                    prims.find(|p| p.accept(&args)).and_then(|p| p.apply(&args))
+                };
+                value.ok_or_else(|| NotFoundError(expr.clone()))
+            }
+        }
+    }
+
+    /// Explains why two terms, which must already be in the database, are equal.
+    pub fn explain_equivalence(&self, a: &Expr, b: &Expr) -> Result<Explanation, Error> {
+        let proofs = self
+            .unionfind
+            .proofs
+            .as_ref()
+            .ok_or(Error::ProofsDisabled)?;
+        let (a_value, b_value) = (self.lookup_term(a)?, self.lookup_term(b)?);
+        if self.bad_find_value(a_value.clone()) != self.bad_find_value(b_value.clone()) {
+            return Err(Error::NotEqual(a.clone(), b.clone()));
+        }
+        (proofs.explain(&a_value, &b_value))
+            .ok_or_else(|| Error::Unexplainable(a.clone(), b.clone()))
+    }
+}
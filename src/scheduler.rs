@@ -0,0 +1,151 @@
+use crate::*;
+
+/// Decides which rules to search and apply on each iteration of a run.
+///
+/// A rule that isn't searched, or whose matches aren't applied, will see those
+/// matches again the next time it is searched and applied. A scheduler is
+/// `Send` and `Sync` so that an [`EGraph`] holding one can be too.
+pub trait Scheduler: SchedulerClone + Send + Sync {
+    /// Whether a run may stop, given that the last iteration made no updates.
+    /// Returning false runs another iteration.
+    fn can_stop(&mut self, _iteration: usize, _rules: &[Symbol]) -> bool {
+        true
+    }
+
+    /// Whether to search the rule on this iteration.
+    fn should_search(&mut self, _iteration: usize, _rule: Symbol) -> bool {
+        true
+    }
+
+    /// Whether to apply the matches that searching the rule found.
+    fn should_apply(&mut self, _iteration: usize, _rule: Symbol, _n_matches: usize) -> bool {
+        true
+    }
+}
+
+/// Lets an [`EGraph`] holding a boxed [`Scheduler`] be cloned.
+pub trait SchedulerClone {
+    fn clone_box(&self) -> Box<dyn Scheduler>;
+}
+
+impl<S: Scheduler + Clone + 'static> SchedulerClone for S {
+    fn clone_box(&self) -> Box<dyn Scheduler> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Scheduler> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// Searches and applies every rule on every iteration.
+#[derive(Clone, Debug, Default)]
+pub struct SimpleScheduler;
+
+impl Scheduler for SimpleScheduler {}
+
+/// Bans rules that match too much, like egg's `BackoffScheduler`.
+///
+/// When a rule finds more than its match limit, its matches are dropped and it
+/// isn't searched for the length of its ban. Each ban doubles both the rule's
+/// match limit and the length of its next ban, up to `usize::MAX`.
+#[derive(Clone, Debug)]
+pub struct BackoffScheduler {
+    match_limit: usize,
+    ban_length: usize,
+    stats: HashMap<Symbol, BackoffStats>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct BackoffStats {
+    times_banned: u32,
+    banned_until: usize,
+}
+
+impl Default for BackoffScheduler {
+    fn default() -> Self {
+        Self {
+            match_limit: 1_000,
+            ban_length: 5,
+            stats: Default::default(),
+        }
+    }
+}
+
+impl BackoffScheduler {
+    /// Sets the number of matches a rule may find before its first ban.
+    pub fn with_match_limit(mut self, match_limit: usize) -> Self {
+        self.match_limit = match_limit;
+        self
+    }
+
+    /// Sets the number of iterations of a rule's first ban.
+    pub fn with_ban_length(mut self, ban_length: usize) -> Self {
+        self.ban_length = ban_length;
+        self
+    }
+}
+
+impl Scheduler for BackoffScheduler {
+    fn can_stop(&mut self, iteration: usize, rules: &[Symbol]) -> bool {
+        let mut banned: Vec<&mut BackoffStats> = self
+            .stats
+            .iter_mut()
+            .filter(|(name, s)| s.banned_until > iteration && rules.contains(name))
+            .map(|(_, s)| s)
+            .collect();
+        let Some(next_unban) = banned.iter().map(|s| s.banned_until).min() else {
+            return true;
+        };
+
+        // nothing else is happening, so bring the bans forward to now
+        let delta = next_unban - iteration;
+        log::debug!("Unbanning {} rules {delta} iterations early", banned.len());
+        for s in &mut banned {
+            s.banned_until -= delta;
+        }
+        false
+    }
+
+    fn should_search(&mut self, iteration: usize, rule: Symbol) -> bool {
+        let stats = self.stats.entry(rule).or_default();
+        if iteration < stats.banned_until {
+            log::debug!(
+                "Skipping {rule} ({} times banned), banned until {}",
+                stats.times_banned,
+                stats.banned_until
+            );
+            false
+        } else {
+            true
+        }
+    }
+
+    fn should_apply(&mut self, iteration: usize, rule: Symbol, n_matches: usize) -> bool {
+        let stats = self.stats.entry(rule).or_default();
+        let threshold = saturating_shl(self.match_limit, stats.times_banned);
+        if n_matches > threshold {
+            let ban_length = saturating_shl(self.ban_length, stats.times_banned);
+            stats.times_banned = stats.times_banned.saturating_add(1);
+            stats.banned_until = iteration.saturating_add(ban_length);
+            log::debug!(
+                "Banning {rule} ({} times banned) for {ban_length} iterations, {n_matches} > {threshold} matches",
+                stats.times_banned,
+            );
+            false
+        } else {
+            true
+        }
+    }
+}
+
+/// `x << n`, or `usize::MAX` if any bits would be shifted out.
+fn saturating_shl(x: usize, n: u32) -> usize {
+    match x.checked_shl(n) {
+        _ if x == 0 => 0,
+        Some(shifted) if shifted >> n == x => shifted,
+        _ => usize::MAX,
+    }
+}
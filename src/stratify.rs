@@ -0,0 +1,149 @@
+use crate::*;
+
+/// The functions a rule reads, and may write to.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Dependencies {
+    reads: HashSet<Symbol>,
+    /// The functions the rule negates or aggregates over,
+    /// which must be fully derived before the rule runs.
+    complete: HashSet<Symbol>,
+    writes: HashSet<Symbol>,
+}
+
+impl EGraph {
+    /// Finds what a rule depends on, given the types of the variables in its head.
+    pub(crate) fn dependencies(
+        &self,
+        query: &Query,
+        head: &[Action],
+        types: &HashMap<Symbol, Type>,
+    ) -> Dependencies {
+        let mut deps = Dependencies::default();
+        deps.reads.extend(query.atoms.iter().map(|atom| atom.0));
+        for fact in &query.negations {
+            fact.for_each_expr(&mut |e| self.add_calls(e, &mut deps.complete));
+        }
+        for agg in &query.aggregates {
+            self.add_reads(&agg.query, &mut deps.complete);
+        }
+
+        for action in head {
+            match action {
+                Action::Define(_, _, e) | Action::Expr(_, e) => {
+                    self.add_calls(e, &mut deps.writes);
+                }
+                Action::Set(_, f, args, e) => {
+                    deps.writes.insert(*f);
+                    for e in args.iter().chain([e]) {
+                        self.add_calls(e, &mut deps.writes);
+                    }
+                }
+                Action::Union(_, a, b) => {
+                    self.add_calls(a, &mut deps.writes);
+                    self.add_calls(b, &mut deps.writes);
+                    // a union can change the output of, or merge tuples in,
+                    // any function that mentions the sort
+                    if let Ok(sort) = self.typecheck_expr(types.clone(), a) {
+                        let mentions_sort = |f: &Function| {
+                            let schema = &f.decl.schema;
+                            schema.output == sort || schema.input.contains(&sort)
+                        };
+                        deps.writes.extend(
+                            (self.functions.iter())
+                                .filter(|(_, f)| mentions_sort(f))
+                                .map(|(name, _)| *name),
+                        );
+                    }
+                }
+                Action::Panic(..) => {}
+            }
+        }
+        deps
+    }
+
+    /// Adds every function the query reads, including in its negations and aggregates.
+    fn add_reads(&self, query: &Query, reads: &mut HashSet<Symbol>) {
+        reads.extend(query.atoms.iter().map(|atom| atom.0));
+        for fact in &query.negations {
+            fact.for_each_expr(&mut |e| self.add_calls(e, reads));
+        }
+        for agg in &query.aggregates {
+            self.add_reads(&agg.query, reads);
+        }
+    }
+
+    /// Adds the functions called in `expr`, which may add tuples to them.
+    fn add_calls(&self, expr: &Expr, calls: &mut HashSet<Symbol>) {
+        expr.walk(
+            &mut |e| {
+                if let Expr::Call(_, op, _) = e {
+                    if self.functions.contains_key(op) {
+                        calls.insert(*op);
+                    }
+                }
+            },
+            &mut |_| {},
+        );
+    }
+
+    /// Splits the rules of a ruleset into strata, so that a rule only runs once
+    /// everything it negates or aggregates over has been fully derived
+    /// by the strata before it. Fails if a rule negates or aggregates over
+    /// a function that depends on the rule itself.
+    pub(crate) fn stratify(&mut self, ruleset: Symbol) -> Result<(), Error> {
+        let in_ruleset = |r: &&Rule| r.ruleset == ruleset;
+
+        // each function points to the functions derived from it
+        let mut derived = HashMap::<Symbol, HashSet<Symbol>>::default();
+        for rule in self.rules.values().filter(in_ruleset) {
+            for f in rule.deps.reads.iter().chain(&rule.deps.complete) {
+                derived.entry(*f).or_default().extend(&rule.deps.writes);
+            }
+        }
+
+        for (name, rule) in self.rules.iter().filter(|(_, r)| in_ruleset(r)) {
+            let mut seen: HashSet<Symbol> = rule.deps.writes.clone();
+            let mut todo: Vec<Symbol> = seen.iter().copied().collect();
+            while let Some(f) = todo.pop() {
+                if rule.deps.complete.contains(&f) {
+                    return Err(Error::NotStratifiable(*name, f));
+                }
+                for g in derived.get(&f).into_iter().flatten() {
+                    if seen.insert(*g) {
+                        todo.push(*g);
+                    }
+                }
+            }
+        }
+
+        // A function is in at least the stratum of what it's derived from,
+        // and in a higher stratum than anything that must be complete to derive it.
+        // This terminates because no cycle goes through a negation or aggregate.
+        let mut strata = HashMap::<Symbol, usize>::default();
+        let stratum = |strata: &HashMap<Symbol, usize>, deps: &Dependencies| {
+            let get = |f: &Symbol| strata.get(f).copied().unwrap_or(0);
+            let reads = deps.reads.iter().map(get);
+            let complete = deps.complete.iter().map(|f| get(f) + 1);
+            reads.chain(complete).max().unwrap_or(0)
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for rule in self.rules.values().filter(in_ruleset) {
+                let s = stratum(&strata, &rule.deps);
+                for f in &rule.deps.writes {
+                    let old = strata.entry(*f).or_default();
+                    if *old < s {
+                        *old = s;
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        for rule in self.rules.values_mut().filter(|r| r.ruleset == ruleset) {
+            rule.stratum = stratum(&strata, &rule.deps);
+        }
+        Ok(())
+    }
+}
@@ -16,17 +16,56 @@ pub enum TypeError {
         reason: String,
     },
     #[error("Tried to unify too many literals: {}", ListDisplay(.0, "\n"))]
-    TooManyLiterals(Vec<Literal>),
+    TooManyLiterals(Vec<Literal>, Span),
     #[error("Unbound symbol {0}")]
-    Unbound(Symbol),
+    Unbound(Symbol, Span),
     #[error("Undefined sort {0}")]
-    UndefinedSort(Symbol),
+    UndefinedSort(Symbol, Span),
     #[error("Function already bound {0}")]
-    FunctionAlreadyBound(Symbol),
+    FunctionAlreadyBound(Symbol, Span),
     #[error("Cannot type a variable as unit: {0}")]
-    UnitVar(Symbol),
+    UnitVar(Symbol, Span),
     #[error("Failed to infer a type for variable: {0}")]
-    InferenceFailure(Symbol),
+    InferenceFailure(Symbol, Span),
+    #[error("Expected a sort, but {expr} has type {actual}")]
+    NotASort { expr: Expr, actual: Type },
+    #[error("No primitive {expr} takes arguments of types {}", ListDisplay(.inputs, " "))]
+    NoMatchingPrimitive { expr: Expr, inputs: Vec<Type> },
+    #[error("Variable {0} is only used by primitives or negations, so nothing binds it")]
+    Ungrounded(Symbol, Span),
+    #[error("Equality {0} needs at least two expressions")]
+    TooFewExprs(Fact),
+}
+
+impl TypeError {
+    /// Where the error is in the source, if it's known.
+    pub fn span(&self) -> Option<Span> {
+        let span = match self {
+            TypeError::Arity { expr, .. }
+            | TypeError::Mismatch { expr, .. }
+            | TypeError::NotASort { expr, .. }
+            | TypeError::NoMatchingPrimitive { expr, .. } => expr.span(),
+            TypeError::Unbound(_, span)
+            | TypeError::UndefinedSort(_, span)
+            | TypeError::FunctionAlreadyBound(_, span)
+            | TypeError::TooManyLiterals(_, span)
+            | TypeError::UnitVar(_, span)
+            | TypeError::InferenceFailure(_, span)
+            | TypeError::Ungrounded(_, span) => *span,
+            TypeError::TooFewExprs(fact) => fact.span(),
+        };
+        Some(span).filter(|span| !span.is_empty())
+    }
+}
+
+fn literal_type(lit: &Literal) -> Type {
+    match lit {
+        Literal::Int(_) => Type::NumType(NumType::I64),
+        Literal::String(_) => Type::String,
+        Literal::Rational(_) => Type::NumType(NumType::Rational),
+        Literal::Bool(_) => Type::Bool,
+        Literal::Unit => Type::Unit,
+    }
 }
 
 #[derive(Debug, Hash, PartialEq, Eq)]
@@ -42,6 +81,11 @@ struct QueryBuilder<'a> {
     unionfind: UnionFind<Info<'a>>,
     nodes: HashMap<ENode, Id>,
     // foo: UnionFind<Info<'a>>,
+    /// The calls to primitives, whose output types depend on their input types.
+    prim_calls: Vec<(&'a Expr, Symbol, Vec<Id>, Id)>,
+    /// Where each variable and literal first appears, to report errors at.
+    var_spans: HashMap<Symbol, Span>,
+    lit_spans: HashMap<Literal, Span>,
     errors: Vec<TypeError>,
     egraph: &'a EGraph,
 }
@@ -121,20 +165,69 @@ impl<'a> QueryBuilder<'a> {
         }
     }
 
-    fn add_fact(&mut self, fact: &'a Fact) -> Id {
+    fn add_fact(&mut self, fact: &'a Fact) {
         match fact {
-            Fact::Eq(exprs) => {
-                assert!(exprs.len() > 1);
+            Fact::Eq(_, exprs) if exprs.len() < 2 => {
+                self.errors.push(TypeError::TooFewExprs(fact.clone()));
+            }
+            Fact::Eq(_, exprs) => {
                 let mut iter = exprs.iter();
                 let mut id = self.add_expr(iter.next().unwrap());
                 for e in iter {
                     let id2 = self.add_expr(e);
                     id = self.unify(id, id2);
                 }
-                id
             }
-            Fact::Fact(e) => self.add_expr_at(e, Type::Unit),
+            Fact::Fact(e @ Expr::Call(_, sym, _)) if self.is_primitive(*sym) => {
+                // a primitive on its own is a guard, so it must be true
+                let id = self.add_expr(e);
+                let ty = Some(Type::Bool);
+                let t = self.add_node(ENode::Literal(Literal::Bool(true)), Info { ty, expr: e });
+                self.unify(id, t);
+            }
+            Fact::Fact(e) => {
+                self.add_expr_at(e, Type::Unit);
+            }
+            Fact::Not(..) => panic!("negations are checked separately"),
+        }
+    }
+
+    fn is_primitive(&self, sym: Symbol) -> bool {
+        !self.egraph.functions.contains_key(&sym) && self.egraph.primitives.contains_key(&sym)
+    }
+
+    /// Types the outputs of primitive calls once the types of their inputs are known,
+    /// which may in turn come from the outputs of other primitive calls.
+    fn type_prim_calls(&mut self) {
+        let mut calls = std::mem::take(&mut self.prim_calls);
+        loop {
+            let n_calls = calls.len();
+            calls.retain(|(expr, sym, ids, id)| {
+                let inputs: Option<Vec<Type>> = ids
+                    .iter()
+                    .map(|&i| self.unionfind.get_value(i).ty.clone())
+                    .collect();
+                let Some(inputs) = inputs else {
+                    return true;
+                };
+                let prims = &self.egraph.primitives[sym];
+                match prims.iter().find(|p| p.input == inputs) {
+                    Some(prim) => {
+                        let ty = Some(prim.output.clone());
+                        self.unify_info(*id, Info { ty, expr });
+                    }
+                    None => self.errors.push(TypeError::NoMatchingPrimitive {
+                        expr: (*expr).clone(),
+                        inputs,
+                    }),
+                }
+                false
+            });
+            if calls.len() == n_calls {
+                break;
+            }
         }
+        // the variables these depend on will fail to be inferred
     }
 
     fn add_node(&mut self, node: ENode, info: Info<'a>) -> Id {
@@ -160,21 +253,24 @@ impl<'a> QueryBuilder<'a> {
 
     fn add_expr(&mut self, expr: &'a Expr) -> Id {
         match expr {
-            Expr::Lit(lit) => {
-                let ty = Some(match lit {
-                    Literal::Int(_) => Type::NumType(NumType::I64),
-                    Literal::String(_) => Type::String,
-                    Literal::Rational(_) => Type::NumType(NumType::Rational),
-                    Literal::Unit => Type::Unit,
-                });
+            Expr::Lit(span, lit) => {
+                self.lit_spans.entry(lit.clone()).or_insert(*span);
+                let ty = Some(literal_type(lit));
                 self.add_node(ENode::Literal(lit.clone()), Info { ty, expr })
             }
-            Expr::Var(var) => {
+            Expr::Var(span, var) => {
+                self.var_spans.entry(*var).or_insert(*span);
                 // TODO handle constants?
                 // FIXME no! constants are distinct from nullary partial functions
                 self.add_node(ENode::Var(*var), Info { ty: None, expr })
             }
-            Expr::Call(sym, args) => {
+            Expr::Call(_, sym, args) if self.is_primitive(*sym) => {
+                let ids: Vec<Id> = args.iter().map(|arg| self.add_expr(arg)).collect();
+                let id = self.add_node(ENode::Node(*sym, ids.clone()), Info { ty: None, expr });
+                self.prim_calls.push((expr, *sym, ids, id));
+                id
+            }
+            Expr::Call(span, sym, args) => {
                 let mut ids = vec![];
                 let ty = if let Some(f) = self.egraph.functions.get(sym) {
                     if args.len() == f.decl.schema.input.len() {
@@ -190,7 +286,7 @@ impl<'a> QueryBuilder<'a> {
                     }
                     Some(f.decl.schema.output.clone())
                 } else {
-                    self.errors.push(TypeError::Unbound(*sym));
+                    self.errors.push(TypeError::Unbound(*sym, *span));
                     None
                 };
 
@@ -210,11 +306,221 @@ impl<'a> QueryBuilder<'a> {
     }
 }
 
+/// Infers the types of the expressions that actions evaluate.
+struct ActionChecker<'a> {
+    /// The types of the variables in scope, other than globals.
+    types: HashMap<Symbol, Type>,
+    errors: Vec<TypeError>,
+    egraph: &'a EGraph,
+}
+
+impl<'a> ActionChecker<'a> {
+    /// The type of `expr`, or None if it has a type error (which is recorded).
+    fn infer(&mut self, expr: &Expr) -> Option<Type> {
+        match expr {
+            Expr::Lit(_, lit) => Some(literal_type(lit)),
+            Expr::Var(span, var) => {
+                let ty = (self.types.get(var))
+                    .or_else(|| self.egraph.global_types.get(var))
+                    .cloned();
+                if ty.is_none() {
+                    self.errors.push(TypeError::Unbound(*var, *span));
+                }
+                ty
+            }
+            Expr::Call(span, op, args) => {
+                if let Some(f) = self.egraph.functions.get(op) {
+                    self.check_args(expr, &f.decl.schema.input, args);
+                    Some(f.decl.schema.output.clone())
+                } else if let Some(prims) = self.egraph.primitives.get(op) {
+                    // infer every argument, so all of their errors are found
+                    let inputs: Vec<Option<Type>> = args.iter().map(|a| self.infer(a)).collect();
+                    let inputs: Vec<Type> = inputs.into_iter().collect::<Option<_>>()?;
+                    let prim = prims.iter().find(|p| p.input == inputs);
+                    if prim.is_none() {
+                        self.errors.push(TypeError::NoMatchingPrimitive {
+                            expr: expr.clone(),
+                            inputs,
+                        });
+                    }
+                    prim.map(|p| p.output.clone())
+                } else {
+                    self.errors.push(TypeError::Unbound(*op, *span));
+                    None
+                }
+            }
+        }
+    }
+
+    fn check(&mut self, expr: &Expr, expected: &Type, reason: &str) {
+        if let Some(actual) = self.infer(expr) {
+            if &actual != expected {
+                self.errors.push(TypeError::Mismatch {
+                    expr: expr.clone(),
+                    expected: expected.clone(),
+                    actual,
+                    reason: reason.into(),
+                });
+            }
+        }
+    }
+
+    /// Checks a fact that will be looked up, rather than matched.
+    fn check_fact(&mut self, fact: &Fact) {
+        match fact {
+            Fact::Eq(_, exprs) if exprs.len() < 2 => {
+                self.errors.push(TypeError::TooFewExprs(fact.clone()));
+            }
+            Fact::Eq(_, exprs) => {
+                let types: Vec<Option<Type>> = exprs.iter().map(|e| self.infer(e)).collect();
+                let expected = types.iter().flatten().next().cloned();
+                for (expr, actual) in exprs.iter().zip(types) {
+                    match (&expected, actual) {
+                        (Some(expected), Some(actual)) if expected != &actual => {
+                            self.errors.push(TypeError::Mismatch {
+                                expr: expr.clone(),
+                                expected: expected.clone(),
+                                actual,
+                                reason: "=".into(),
+                            })
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Fact::Fact(expr) => {
+                // a relation, or a guard
+                match self.infer(expr) {
+                    Some(Type::Unit | Type::Bool) | None => {}
+                    Some(actual) => self.errors.push(TypeError::Mismatch {
+                        expr: expr.clone(),
+                        expected: Type::Unit,
+                        actual,
+                        reason: "fact".into(),
+                    }),
+                }
+            }
+            Fact::Not(_, fact) => self.check_fact(fact),
+        }
+    }
+
+    /// Checks the arguments of `call` against the input types of its function.
+    fn check_args(&mut self, call: &Expr, input: &[Type], args: &[Expr]) {
+        if args.len() == input.len() {
+            for (arg, ty) in args.iter().zip(input) {
+                self.check(arg, ty, "argument");
+            }
+        } else {
+            self.errors.push(TypeError::Arity {
+                expr: call.clone(),
+                expected: input.len(),
+            });
+            for arg in args {
+                self.infer(arg);
+            }
+        }
+    }
+
+    fn check_action(&mut self, action: &Action) {
+        match action {
+            Action::Define(_, var, expr) => {
+                if let Some(ty) = self.infer(expr) {
+                    self.types.insert(*var, ty);
+                }
+            }
+            Action::Set(span, f, args, expr) => {
+                if let Some(function) = self.egraph.functions.get(f) {
+                    let call = Expr::Call(*span, *f, args.clone());
+                    self.check_args(&call, &function.decl.schema.input, args);
+                    self.check(expr, &function.decl.schema.output, "set");
+                } else {
+                    self.errors.push(TypeError::Unbound(*f, *span));
+                }
+            }
+            Action::Union(_, a, b) => {
+                if let (Some(a_ty), Some(b_ty)) = (self.infer(a), self.infer(b)) {
+                    if !a_ty.is_sort() {
+                        self.errors.push(TypeError::NotASort {
+                            expr: a.clone(),
+                            actual: a_ty,
+                        });
+                    } else if a_ty != b_ty {
+                        self.errors.push(TypeError::Mismatch {
+                            expr: b.clone(),
+                            expected: a_ty,
+                            actual: b_ty,
+                            reason: "union".into(),
+                        });
+                    }
+                }
+            }
+            Action::Panic(..) => {}
+            Action::Expr(_, expr) => {
+                self.infer(expr);
+            }
+        }
+    }
+}
+
 impl EGraph {
+    fn action_checker(&self, types: HashMap<Symbol, Type>) -> ActionChecker<'_> {
+        ActionChecker {
+            types,
+            errors: vec![],
+            egraph: self,
+        }
+    }
+
+    /// Infers the type of an expression whose variables have the given types
+    /// (or are globals).
+    pub(crate) fn typecheck_expr(
+        &self,
+        types: HashMap<Symbol, Type>,
+        expr: &Expr,
+    ) -> Result<Type, Error> {
+        let mut checker = self.action_checker(types);
+        match checker.infer(expr) {
+            Some(ty) if checker.errors.is_empty() => Ok(ty),
+            _ => Err(Error::TypeErrors(checker.errors)),
+        }
+    }
+
+    /// Typechecks actions whose variables have the given types (or are globals),
+    /// returning those types along with the types of the variables they define.
+    pub(crate) fn typecheck_actions(
+        &self,
+        types: HashMap<Symbol, Type>,
+        actions: &[Action],
+    ) -> Result<HashMap<Symbol, Type>, Error> {
+        let mut checker = self.action_checker(types);
+        for action in actions {
+            checker.check_action(action);
+        }
+        if checker.errors.is_empty() {
+            Ok(checker.types)
+        } else {
+            Err(Error::TypeErrors(checker.errors))
+        }
+    }
+
     pub(crate) fn compile_query(&self, facts: Vec<Fact>) -> Result<Query, Error> {
+        let (facts, aggregates) = self.extract_aggregates(facts)?;
+        let (negations, facts): (Vec<Fact>, Vec<Fact>) =
+            facts.into_iter().partition(|f| matches!(f, Fact::Not(..)));
+        // the variables an aggregate shares with the query have the types it found for them
+        let typed_vars: Vec<(Expr, Type)> = (aggregates.iter())
+            .flat_map(|agg| {
+                let keys = agg.keys.iter().map(|k| (*k, agg.query.types[k].clone()));
+                keys.chain([(agg.output, agg.ty.clone())])
+            })
+            .map(|(var, ty)| (Expr::var(var), ty))
+            .collect();
         let mut builder = QueryBuilder {
             unionfind: Default::default(),
             nodes: Default::default(),
+            prim_calls: Default::default(),
+            var_spans: Default::default(),
+            lit_spans: Default::default(),
             errors: Default::default(),
             egraph: self,
         };
@@ -222,8 +528,12 @@ impl EGraph {
         for fact in &facts {
             builder.add_fact(fact);
         }
+        for (var, ty) in &typed_vars {
+            builder.add_expr_at(var, ty.clone());
+        }
 
         builder.rebuild();
+        builder.type_prim_calls();
         let mut query = Query::default();
 
         #[derive(Default, Clone)]
@@ -249,9 +559,13 @@ impl EGraph {
             assert!(class.lits.len() + class.vars.len() + class.nodes.len() > 0);
             let atomterm = if let Some(lit) = class.lits.first() {
                 if class.lits.len() > 1 {
-                    builder
-                        .errors
-                        .push(TypeError::TooManyLiterals(class.lits.clone()));
+                    // the literal that appears last is the one that conflicts
+                    let span = (class.lits.iter())
+                        .filter_map(|lit| builder.lit_spans.get(lit).copied())
+                        .max_by_key(|span| span.start)
+                        .unwrap_or_default();
+                    let lits = class.lits.clone();
+                    builder.errors.push(TypeError::TooManyLiterals(lits, span));
                 }
                 AtomTerm::Value(lit.to_value())
             } else {
@@ -271,16 +585,15 @@ impl EGraph {
             let info = builder.unionfind.get_value(id);
             let atomterm = class.atomterm.clone().unwrap();
             for &var in &class.vars {
-                // TODO do something with the type
-                let _ty = if let Some(ty) = info.ty.clone() {
+                let span = builder.var_spans[&var];
+                if let Some(ty) = info.ty.clone() {
                     if ty == Type::Unit {
-                        builder.errors.push(TypeError::UnitVar(var));
+                        builder.errors.push(TypeError::UnitVar(var, span));
                     }
-                    ty
+                    query.types.insert(var, ty);
                 } else {
-                    builder.errors.push(TypeError::InferenceFailure(var));
-                    Type::Unit
-                };
+                    builder.errors.push(TypeError::InferenceFailure(var, span));
+                }
                 query.bindings.insert(var, atomterm.clone());
             }
 
@@ -290,9 +603,95 @@ impl EGraph {
                     .map(|c| classes[c].atomterm.clone().unwrap())
                     .collect();
                 terms.push(atomterm.clone());
-                query.atoms.push(Atom(*sym, terms))
+                if self.functions.contains_key(sym) {
+                    query.atoms.push(Atom(*sym, terms))
+                } else if let Some(prims) = self.primitives.get(sym) {
+                    let inputs: Option<Vec<Type>> = children
+                        .iter()
+                        .map(|&c| builder.unionfind.get_value(c).ty.clone())
+                        .collect();
+                    // a primitive that can't be typed has already been reported
+                    if let Some(prim) = prims.iter().find(|p| Some(&p.input) == inputs.as_ref()) {
+                        query.prims.push(PrimAtom(*sym, prim.clone(), terms));
+                    }
+                }
+            }
+        }
+
+        // Variables must be bound by atoms, or computed by primitives
+        // and aggregates from variables that are.
+        let mut is_bound = HashSet::<AtomTerm>::default();
+        is_bound.extend(query.atoms.iter().flat_map(|a| a.1.iter().cloned()));
+        let computed: Vec<(Vec<AtomTerm>, AtomTerm)> = (query.prims.iter())
+            .map(|prim| {
+                let (output, inputs) = prim.2.split_last().unwrap();
+                (inputs.to_vec(), output.clone())
+            })
+            .chain(aggregates.iter().map(|agg| {
+                let keys = agg.keys.iter().map(|k| query.bindings[k].clone());
+                (keys.collect(), query.bindings[&agg.output].clone())
+            }))
+            .collect();
+        loop {
+            let n_bound = is_bound.len();
+            for (inputs, output) in &computed {
+                if inputs
+                    .iter()
+                    .all(|t| matches!(t, AtomTerm::Value(_)) || is_bound.contains(t))
+                {
+                    is_bound.insert(output.clone());
+                }
+            }
+            if is_bound.len() == n_bound {
+                break;
+            }
+        }
+        let mut ungrounded: Vec<Symbol> = (query.bindings.iter())
+            .filter(|(_, t)| matches!(t, AtomTerm::Var(_)) && !is_bound.contains(t))
+            .map(|(var, _)| *var)
+            // an aggregate is ungrounded because one of its keys is
+            .filter(|var| !aggregates.iter().any(|agg| agg.output == *var))
+            .collect();
+        ungrounded.sort_by_key(|var| var.as_str());
+        // nothing could give these a type, but that's not the real problem
+        builder.errors.retain(
+            |e| !matches!(e, TypeError::InferenceFailure(var, _) if ungrounded.contains(var)),
+        );
+        let var_spans = &builder.var_spans;
+        builder.errors.extend(
+            (ungrounded.into_iter()).map(|var| TypeError::Ungrounded(var, var_spans[&var])),
+        );
+
+        // Negations are looked up once their variables are bound,
+        // so they can't bind any themselves.
+        let mut checker = self.action_checker(query.types.clone());
+        for fact in &negations {
+            let mut ungrounded: Vec<(Symbol, Span)> = vec![];
+            fact.for_each_expr(&mut |e| {
+                e.walk(
+                    &mut |e| {
+                        if let Expr::Var(span, var) = e {
+                            if !query.bindings.contains_key(var)
+                                && !ungrounded.iter().any(|(v, _)| v == var)
+                            {
+                                ungrounded.push((*var, *span));
+                            }
+                        }
+                    },
+                    &mut |_| {},
+                )
+            });
+            if ungrounded.is_empty() {
+                checker.check_fact(fact);
+            } else {
+                (builder.errors).extend(
+                    (ungrounded.into_iter()).map(|(var, span)| TypeError::Ungrounded(var, span)),
+                );
             }
         }
+        builder.errors.extend(checker.errors);
+        query.negations = negations;
+        query.aggregates = aggregates;
 
         if builder.errors.is_empty() {
             log::debug!("Compiled {facts:?} to {query:?}");
@@ -1,4 +1,4 @@
-use crate::{util::IndexMap, Id, Value};
+use crate::{proofs::*, util::IndexMap, Id, Value};
 
 use std::fmt::Debug;
 use std::hash::Hash;
@@ -8,6 +8,10 @@ use std::hash::Hash;
 pub struct UnionFind<V = ()> {
     parents: Vec<(Id, V)>,
     n_unions: usize,
+    /// Ids that stopped being leaders since the last call to `take_dirty`.
+    dirty: Vec<Id>,
+    /// Justifies every union, if proofs are enabled.
+    pub(crate) proofs: Option<ProofForest>,
 }
 
 impl<V> Default for UnionFind<V> {
@@ -15,6 +19,8 @@ impl<V> Default for UnionFind<V> {
         Self {
             parents: Default::default(),
             n_unions: Default::default(),
+            dirty: Default::default(),
+            proofs: None,
         }
     }
 }
@@ -24,9 +30,17 @@ impl<V> UnionFind<V> {
         self.n_unions
     }
 
+    /// Takes the ids that have stopped being leaders since the last call.
+    pub fn take_dirty(&mut self) -> Vec<Id> {
+        std::mem::take(&mut self.dirty)
+    }
+
     pub fn make_set_with(&mut self, value: V) -> Id {
         let id = Id::from(self.parents.len());
         self.parents.push((id, value));
+        if let Some(proofs) = &mut self.proofs {
+            proofs.add_id();
+        }
         id
     }
 }
@@ -40,8 +54,44 @@ impl UnionFind<()> {
         self.find_mut(value.into()).into()
     }
 
-    pub fn union_values(&mut self, value1: Value, value2: Value) -> Value {
-        self.union(value1.into(), value2.into()).into()
+    /// Unions two values, calling `reason` to justify it if proofs are enabled.
+    pub(crate) fn union_values(
+        &mut self,
+        value1: Value,
+        value2: Value,
+        reason: impl FnOnce() -> Justification,
+    ) -> Value {
+        let (id1, id2) = (Id::from(value1), Id::from(value2));
+        if let Some(mut proofs) = self.proofs.take() {
+            if self.find(id1) != self.find(id2) {
+                proofs.add_edge(id1, id2, reason());
+            }
+            self.proofs = Some(proofs);
+        }
+        self.union(id1, id2).into()
+    }
+
+    /// Unions two values without recording why, so that with proofs enabled,
+    /// explaining why they're equal fails rather than giving a wrong reason.
+    pub(crate) fn union_unexplained(&mut self, value1: Value, value2: Value) -> Value {
+        self.union(Id::from(value1), Id::from(value2)).into()
+    }
+
+    /// Puts a new id into the class of `existing`, keeping its leader. This
+    /// isn't counted as a union, since it says nothing new about the database.
+    pub(crate) fn union_fresh(
+        &mut self,
+        existing: Value,
+        fresh: Id,
+        reason: impl FnOnce() -> Justification,
+    ) {
+        let existing = Id::from(existing);
+        debug_assert_eq!(self.find(fresh), fresh);
+        if let Some(proofs) = &mut self.proofs {
+            proofs.add_edge(fresh, existing, reason());
+        }
+        let root = self.find_mut(existing);
+        self.parents[usize::from(fresh)].0 = root;
     }
 }
 
@@ -81,8 +131,9 @@ impl<V: UnifyValue> UnionFindLike<Id, V> for UnionFind<V> {
         self.parents[index].0 = new_parent.into();
     }
 
-    fn did_union(&mut self, _: usize) {
+    fn did_union(&mut self, _root: usize, child: usize) {
         self.n_unions += 1;
+        self.dirty.push(Id::from(child));
     }
 }
 
@@ -116,6 +167,7 @@ pub(crate) trait UnionFindLike<K: UnifyKey, V: UnifyValue> {
             .unwrap_or_else(|| panic!("Couldn't find key {key:?}"))
     }
 
+    #[expect(dead_code, reason = "kept as the counterpart of len")]
     fn is_empty(&self) -> bool {
         self.len() == 0
     }
@@ -201,11 +253,11 @@ pub(crate) trait UnionFindLike<K: UnifyKey, V: UnifyValue> {
         let v = V::merge(self.get_value_index(a), self.get_value_index(b))?;
         self.set_value_index(a, v);
         self.set_parent_index(b, a);
-        self.did_union(a);
+        self.did_union(a, b);
         Ok(a)
     }
 
-    fn did_union(&mut self, _index: usize) {}
+    fn did_union(&mut self, _root: usize, _child: usize) {}
 
     fn sets(&self) -> Vec<Vec<K>>
     where
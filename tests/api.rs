@@ -0,0 +1,965 @@
+use egg_smol::{ast::*, *};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[test]
+fn run_until_saturated() {
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program(
+            "(relation edge (i64 i64))
+             (relation path (i64 i64))
+             (rule ((edge x y)) ((path x y)))
+             (rule ((path x y) (edge y z)) ((path x z)))
+             (edge 1 2) (edge 2 3) (edge 3 4)",
+        )
+        .unwrap();
+
+    let report = egraph.run_rules(1).unwrap();
+    assert_eq!(report.iterations, 1);
+    assert!(!report.saturated);
+    assert!(report.updates > 0);
+
+    let report = egraph.run_until_saturated().unwrap();
+    assert!(report.saturated);
+    let report = egraph.run_rules(10).unwrap();
+    assert_eq!(report.iterations, 1);
+    assert!(report.saturated);
+    assert_eq!(report.updates, 0);
+}
+
+#[test]
+fn explain_query() {
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program(
+            "(relation edge (i64 i64))
+             (edge 1 2) (edge 2 3) (edge 3 4) (edge 3 3)",
+        )
+        .unwrap();
+
+    let var = |v: &str| Expr::var(v);
+    let facts = vec![
+        Fact::Fact(Expr::call("edge", [var("x"), var("y")])),
+        Fact::Fact(Expr::call("edge", [var("y"), var("z")])),
+    ];
+    let explanation = egraph.explain_query(facts).unwrap();
+    assert_eq!(explanation.n_matches(), 5);
+    assert!(explanation.to_string().contains("variable order"));
+}
+
+#[test]
+fn primitive_guards() {
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program(
+            "(relation edge (i64 i64))
+             (edge 1 2) (edge 2 3) (edge 3 4) (edge 3 3)",
+        )
+        .unwrap();
+
+    let var = |v: &str| Expr::var(v);
+    let facts = vec![
+        Fact::Fact(Expr::call("edge", [var("x"), var("y")])),
+        Fact::Fact(Expr::call("!=", [var("x"), var("y")])),
+        Fact::Eq(
+            Span::default(),
+            vec![var("z"), Expr::call("+", [var("y"), Expr::lit(1)])],
+        ),
+        Fact::Fact(Expr::call("edge", [var("y"), var("z")])),
+    ];
+    let explanation = egraph.explain_query(facts).unwrap();
+    assert_eq!(explanation.n_matches(), 2);
+    assert!(explanation.to_string().contains("primitives"));
+
+    // a variable only used by primitives can't be matched
+    let facts = vec![
+        Fact::Fact(Expr::call("edge", [var("x"), var("y")])),
+        Fact::Fact(Expr::call("<", [var("x"), var("w")])),
+    ];
+    assert!(matches!(
+        egraph.explain_query(facts),
+        Err(Error::TypeErrors(errs)) if matches!(errs[..], [TypeError::Ungrounded(..)])
+    ));
+}
+
+#[test]
+fn user_primitives() {
+    let i64 = || Type::NumType(NumType::I64);
+    let mut egraph = EGraph::default();
+    egraph
+        .add_primitive("bitand", vec![i64(), i64()], i64(), |args| {
+            let (a, b) = (i64::from(args[0].clone()), i64::from(args[1].clone()));
+            Some(Value::from(a & b))
+        })
+        .unwrap();
+    // partial, with no result when dividing by zero
+    egraph
+        .add_primitive("div", vec![i64(), i64()], i64(), |args| {
+            let (a, b) = (i64::from(args[0].clone()), i64::from(args[1].clone()));
+            a.checked_div(b).map(Value::from)
+        })
+        .unwrap();
+    // overloaded on its input types
+    egraph
+        .add_primitive("describe", vec![i64()], Type::String, |args| {
+            let n = i64::from(args[0].clone());
+            Some(Value::from(Symbol::from(format!("number {n}"))))
+        })
+        .unwrap();
+    egraph
+        .add_primitive("describe", vec![Type::Bool], Type::String, |args| {
+            let b = bool::from(args[0].clone());
+            Some(Value::from(Symbol::from(format!("bool {b}"))))
+        })
+        .unwrap();
+
+    egraph
+        .parse_and_run_program(
+            "(relation pair (i64 i64))
+             (relation quotient (i64 i64 i64))
+             (relation label (String))
+             (relation divided (i64 i64))
+             (rule ((pair x y) (= q (div x y))) ((quotient x y q) (divided x y)))
+             (rule ((pair x y)) ((label (describe (div y x)))))
+             (rule ((pair x y) (= (bitand x y) 0)) ((label (describe true))))
+             (pair 7 2) (pair 4 0) (pair 0 3)
+             (run)
+             (check (quotient 7 2 3))
+             (check (quotient 0 3 0))
+             (check (not (divided 4 0)))
+             (check (label (describe 0)))
+             (check (label (describe true)))
+             (check (= (describe (bitand 6 3)) (describe 2)))",
+        )
+        .unwrap();
+    // so is calling one without a result
+    assert!(matches!(
+        egraph.parse_and_run_program("(check (label (describe (div 4 0))))"),
+        Err(Error::NotFoundError(_))
+    ));
+
+    assert!(matches!(
+        egraph.add_primitive("div", vec![i64(), i64()], i64(), |_| None),
+        Err(Error::PrimitiveAlreadyBound(..))
+    ));
+    egraph.declare_sort("Math").unwrap();
+    assert!(matches!(
+        egraph.add_primitive("size", vec![Type::Sort("Math".into())], i64(), |_| None),
+        Err(Error::InvalidPrimitiveType(..))
+    ));
+    assert!(matches!(
+        egraph.parse_and_run_program("(rule ((label s) (= t (describe s))) ((label t)))"),
+        Err(Error::TypeErrors(errs)) if matches!(errs[0], TypeError::NoMatchingPrimitive { .. })
+    ));
+}
+
+#[test]
+fn stratified_negation() {
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program(
+            "(relation edge (i64 i64))
+             (relation path (i64 i64))
+             (relation no-path (i64 i64))
+             (rule ((edge x y)) ((path x y)))
+             (rule ((path x y) (edge y z)) ((path x z)))
+             (rule ((edge x y) (edge z w) (not (path x w))) ((no-path x w)))
+             (edge 1 2) (edge 3 4)",
+        )
+        .unwrap();
+    let report = egraph.run_until_saturated().unwrap();
+    assert!(report.saturated);
+    egraph
+        .parse_and_run_program("(check (no-path 1 4)) (check (not (no-path 1 2)))")
+        .unwrap();
+    // the strata share the limit on iterations, and path needs four more
+    egraph
+        .parse_and_run_program("(edge 5 6) (edge 6 7) (edge 7 8)")
+        .unwrap();
+    let report = egraph.run_rules(2).unwrap();
+    assert_eq!((report.iterations, report.saturated), (2, false));
+
+    // path depends on no-path, which negates path
+    assert!(matches!(
+        egraph.parse_and_run_program("(rule ((no-path x y)) ((path x y)))"),
+        Err(Error::NotStratifiable(..))
+    ));
+    // the rule that failed isn't kept
+    egraph.run_until_saturated().unwrap();
+    egraph
+        .parse_and_run_program("(check (not (path 1 4)))")
+        .unwrap();
+
+    // negations can't bind variables
+    assert!(matches!(
+        egraph.parse_and_run_program("(rule ((edge x y) (not (path y z))) ((path x x)))"),
+        Err(Error::TypeErrors(errs)) if matches!(errs[..], [TypeError::Ungrounded(..)])
+    ));
+    assert!(matches!(
+        egraph.parse_and_run_program("(check (not (edge 1 2)))"),
+        Err(Error::NegatedCheckError(_))
+    ));
+}
+
+#[test]
+fn aggregates() {
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program(
+            "(relation edge (i64 i64))
+             (relation label (i64 String))
+             (function degree (i64) i64)
+             (rule ((edge x y) (= n (count z (edge x z)))) ((set (degree x) n)))
+             (edge 1 2) (edge 1 3) (edge 2 3)",
+        )
+        .unwrap();
+    egraph.run_until_saturated().unwrap();
+    egraph
+        .parse_and_run_program("(check (= (degree 1) 2)) (check (= (degree 2) 1))")
+        .unwrap();
+
+    // edge would depend on its own count
+    assert!(matches!(
+        egraph.parse_and_run_program("(rule ((= n (count x (edge x y)))) ((edge n n)))"),
+        Err(Error::NotStratifiable(..))
+    ));
+    // strings can't be summed
+    assert!(matches!(
+        egraph.parse_and_run_program("(rule ((edge x y) (= s (sum l (label x l)))) ((edge x x)))"),
+        Err(Error::TypeError(TypeError::Mismatch { .. }))
+    ));
+}
+
+#[test]
+fn backoff_scheduler_saturates() {
+    let mut egraph = EGraph::default();
+    egraph.set_scheduler(
+        BackoffScheduler::default()
+            .with_match_limit(2)
+            .with_ban_length(100),
+    );
+    egraph
+        .parse_and_run_program(
+            "(relation edge (i64 i64))
+             (relation path (i64 i64))
+             (rule ((edge x y)) ((path x y)))
+             (rule ((path x y) (edge y z)) ((path x z)))
+             (edge 1 2) (edge 2 3) (edge 3 4) (edge 4 5) (edge 5 6)",
+        )
+        .unwrap();
+
+    // banned rules are let back in rather than stopping early
+    let report = egraph.run_until_saturated().unwrap();
+    assert!(report.saturated);
+    egraph.parse_and_run_program("(check (path 1 6))").unwrap();
+
+    // a rule banned over and over gets limits and bans that saturate
+    let mut egraph = EGraph::default();
+    egraph.set_scheduler(
+        BackoffScheduler::default()
+            .with_match_limit(0)
+            .with_ban_length(usize::MAX / 2),
+    );
+    egraph
+        .parse_and_run_program(
+            "(relation edge (i64 i64))
+             (relation path (i64 i64))
+             (rule ((edge x y)) ((path x y)))
+             (edge 1 2)",
+        )
+        .unwrap();
+    let report = egraph.run_rules(100).unwrap();
+    assert_eq!(report.iterations, 100);
+    assert!(egraph.parse_and_run_program("(check (path 1 2))").is_err());
+}
+
+#[test]
+fn run_limits() {
+    let program = "
+        (datatype Nat (Z) (S Nat))
+        (rule ((= n (S m))) ((S n)))
+        (S (Z))";
+
+    let mut egraph = EGraph::default();
+    egraph.parse_and_run_program(program).unwrap();
+    egraph.set_run_config(RunConfig {
+        node_limit: Some(100),
+        ..Default::default()
+    });
+    let report = egraph.run_rules(1000).unwrap();
+    assert_eq!(report.stop_reason, Some(StopReason::NodeLimit(100)));
+    assert!(report.iterations < 1000);
+    assert!(!report.saturated);
+
+    let cancel = Arc::new(AtomicBool::new(true));
+    let mut egraph = EGraph::default();
+    egraph.parse_and_run_program(program).unwrap();
+    egraph.set_run_config(RunConfig {
+        cancel: Some(cancel.clone()),
+        ..Default::default()
+    });
+    let report = egraph.run_rules(1000).unwrap();
+    assert_eq!(report.stop_reason, Some(StopReason::Cancelled));
+    assert_eq!(report.iterations, 0);
+
+    cancel.store(false, Ordering::Relaxed);
+    let report = egraph.run_rules(10).unwrap();
+    assert_eq!(report.iterations, 10);
+    assert_eq!(report.stop_reason, None);
+}
+
+#[test]
+fn rule_stats() {
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program(
+            "(relation edge (i64 i64))
+             (relation path (i64 i64))
+             (rule ((edge x y)) ((path x y)))
+             (rule ((path x y) (edge y z)) ((path x z)))
+             (edge 1 2) (edge 2 3)",
+        )
+        .unwrap();
+    let report = egraph.run_until_saturated().unwrap();
+    assert_eq!(report.iterations, 3);
+
+    let stats: Vec<RuleStats> = egraph.rule_stats().map(|(_, s)| s.clone()).collect();
+    assert_eq!(stats.len(), 2);
+    assert!(stats.iter().all(|s| s.searches == 3));
+    assert_eq!(stats.iter().map(|s| s.matches).sum::<usize>(), 3);
+    assert_eq!(stats.iter().map(|s| s.changes).sum::<usize>(), 3);
+
+    assert!(egraph.rule_stats_table().contains("searches"));
+}
+
+#[test]
+fn merge_on_rebuild() {
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program(
+            "(datatype Math (Var String))
+             (function size (Math) i64)
+             (define x (Var \"x\"))
+             (define y (Var \"y\"))
+             (define z (Var \"z\"))
+             (set (size x) 1)
+             (set (size y) 2)
+             (set (size z) 1)
+             (union x y)
+             (union y z)",
+        )
+        .unwrap();
+    assert!(matches!(egraph.rebuild(), Err(Error::MergeError(..))));
+    // the tuple that was there is kept, rather than both being lost
+    let size = |e: &mut EGraph, var| {
+        let var = Expr::call("Var", [Expr::lit(Symbol::from(var))]);
+        e.eval_closed_expr(&Expr::call("size", [var])).unwrap()
+    };
+    let kept = size(&mut egraph, "\"x\"");
+    assert!(kept == Value::from(1) || kept == Value::from(2));
+    assert_eq!(size(&mut egraph, "\"z\""), kept);
+
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program(
+            "(datatype Math (Var String))
+             (function size (Math) i64 :merge (max old new))
+             (define x (Var \"x\"))
+             (define y (Var \"y\"))
+             (set (size x) 1)
+             (set (size y) 2)
+             (union x y)",
+        )
+        .unwrap();
+    egraph.rebuild().unwrap();
+    egraph
+        .parse_and_run_program("(check (= (size (Var \"x\")) 2))")
+        .unwrap();
+}
+
+#[test]
+fn typecheck_actions() {
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program(
+            "(datatype Math (Num i64) (Add Math Math))
+             (datatype Bool (True))
+             (function size (Math) i64 :merge (max old new))",
+        )
+        .unwrap();
+
+    let bad_programs = [
+        // unbound variable in a rule head
+        "(rule ((= e (Num n))) ((union e (Num m))))",
+        // arity mismatch
+        "(rule ((= e (Num n))) ((Add e)))",
+        // union of different sorts
+        "(union (Num 1) (True))",
+        // union of things that aren't ids
+        "(union 1 2)",
+        // set on an undeclared function
+        "(set (depth (Num 1)) 1)",
+        // set with the wrong output type
+        "(set (size (Num 1)) \"big\")",
+        // no overload of a primitive
+        "(define x (+ 1 \"2\"))",
+        // merge and default expressions
+        "(function bad (Math) i64 :merge \"old\")",
+        "(function worse (Math) Math :default 0)",
+    ];
+    for program in bad_programs {
+        match egraph.parse_and_run_program(program) {
+            Err(Error::TypeErrors(_) | Error::TypeError(_)) => {}
+            result => panic!("expected a type error for {program}, got {result:?}"),
+        }
+    }
+    // nothing was run
+    assert!(egraph.parse_and_run_program("(check (Num 1))").is_err());
+
+    egraph
+        .parse_and_run_program(
+            "(rule ((= e (Add a b))) ((define s (+ (size a) (size b))) (set (size e) s)))
+             (define two (Add (Num 1) (Num 1)))
+             (extract two)",
+        )
+        .unwrap();
+}
+
+#[test]
+fn error_spans() {
+    let program = "(datatype Math (Num i64) (Add Math Math))
+(rule ((= e (Num n)))
+      ((union e (Add e \"two\"))))";
+    let mut egraph = EGraph::default();
+    let err = egraph.parse_and_run_program(program).unwrap_err();
+    let span = err.span().unwrap();
+    assert_eq!(&program[span.start..span.end], "\"two\"");
+    assert_eq!(
+        err.render("math.egg", program),
+        format!(
+            "error: {err}
+ --> math.egg:3:24
+  |
+3 |       ((union e (Add e \"two\"))))
+  |                        ^^^^^"
+        )
+    );
+
+    let err = egraph.parse_and_run_program("(check (= 1))").unwrap_err();
+    let span = err.span().unwrap();
+    assert_eq!(span.start, "(check (= 1".len());
+
+    // errors found while running point at what was run
+    let program = "(check (= (Num 1) (Num 2)))";
+    let err = egraph.parse_and_run_program(program).unwrap_err();
+    assert!(matches!(err, Error::CheckError(..)));
+    assert_eq!(
+        err.render("math.egg", program),
+        format!(
+            "error: {err}
+ --> math.egg:1:8
+  |
+1 | (check (= (Num 1) (Num 2)))
+  |        ^^^^^^^^^^^^^^^^^^^"
+        )
+    );
+    let program = "(rule ((= e (Num x)) (< x y)) ())";
+    let span = egraph.parse_and_run_program(program).unwrap_err().span();
+    assert_eq!(span.map(|span| &program[span.start..span.end]), Some("y"));
+    let program = "(extract :only (Num Sub) (Num 1))";
+    let span = egraph.parse_and_run_program(program).unwrap_err().span();
+    assert_eq!(
+        span.map(|span| &program[span.start..span.end]),
+        Some(program)
+    );
+
+    // spans don't change what an expression is
+    assert_eq!(
+        Expr::call("Num", [Expr::lit(1)]),
+        Expr::Call(Span::new(3, 10), "Num".into(), vec![Expr::lit(1)])
+    );
+}
+
+#[test]
+fn errors_instead_of_panics() {
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program(
+            "(datatype Math (Num i64))
+             (function size (Math) i64)
+             (relation edge (i64 i64))
+             (define one (Num 1))
+             (rule ((edge x y)) ((edge y x)))",
+        )
+        .unwrap();
+
+    assert!(matches!(
+        egraph.parse_and_run_program("(rule ((edge x y)) ((edge y x)))"),
+        Err(Error::RuleAlreadyBound(..))
+    ));
+    assert!(matches!(
+        egraph.parse_and_run_program("(define one (Num 2))"),
+        Err(Error::GlobalAlreadyBound(..))
+    ));
+    assert!(matches!(
+        egraph.parse_and_run_program("(check 1)"),
+        Err(Error::InvalidCheck(_))
+    ));
+    assert!(matches!(
+        egraph.parse_and_run_program("(check (size one))"),
+        Err(Error::TypeError(TypeError::Mismatch { .. }))
+    ));
+    assert!(matches!(
+        egraph.parse_and_run_program("(panic \"oh no\")"),
+        Err(Error::Panic(_))
+    ));
+    assert!(matches!(
+        egraph.parse_and_run_program("(run -1)"),
+        Err(Error::ParseError(_))
+    ));
+    assert!(matches!(
+        egraph.parse_and_run_program("(edge 99999999999999999999 1)"),
+        Err(Error::ParseError(_))
+    ));
+    match egraph.parse_and_run_program("(extract 1//0)") {
+        Err(err @ Error::ParseError(_)) => {
+            assert_eq!(err.to_string(), "1//0 at 9..13 has a zero denominator.")
+        }
+        result => panic!("expected a parse error, got {result:?}"),
+    }
+    assert!(matches!(
+        egraph.eval_closed_expr(&Expr::var("two")),
+        Err(Error::TypeError(TypeError::Unbound(..)))
+    ));
+    assert!(matches!(
+        egraph.eval_closed_expr(&Expr::call("depth", [])),
+        Err(Error::TypeError(TypeError::Unbound(..)))
+    ));
+    // a non-id output without a default can't be made up
+    assert!(matches!(
+        egraph.eval_closed_expr(&Expr::call("size", [Expr::var("one")])),
+        Err(Error::NotFoundError(_))
+    ));
+    let facts = vec![Fact::Eq(Span::default(), vec![Expr::lit(1), Expr::lit(2)])];
+    assert!(matches!(
+        Query::from_facts(facts),
+        Err(Error::TypeError(TypeError::TooManyLiterals(..)))
+    ));
+
+    // a panic in a rule stops the run
+    egraph
+        .parse_and_run_program(
+            "(rule ((edge 1 x)) ((panic \"found an edge\")))
+         (edge 1 2)",
+        )
+        .unwrap();
+    assert!(matches!(egraph.run_rules(10), Err(Error::Panic(_))));
+}
+
+#[test]
+fn explain_equivalence() {
+    let program = "
+        (datatype Math (Num i64) (Add Math Math) (Mul Math Math))
+        (rewrite (Add (Num a) (Num b)) (Num (+ a b)))
+        (Mul (Add (Num 1) (Num 2)) (Num 4))
+        (Mul (Num 3) (Num 4))";
+    let num = |n: i64| Expr::call("Num", [Expr::lit(n)]);
+    let lhs = Expr::call("Mul", [Expr::call("Add", [num(1), num(2)]), num(4)]);
+    let rhs = Expr::call("Mul", [num(3), num(4)]);
+
+    let mut egraph = EGraph::default();
+    egraph.parse_and_run_program(program).unwrap();
+    egraph.run_until_saturated().unwrap();
+    assert!(matches!(
+        egraph.explain_equivalence(&lhs, &rhs),
+        Err(Error::ProofsDisabled)
+    ));
+
+    let mut egraph = EGraph::default();
+    egraph.enable_proofs().unwrap();
+    egraph.parse_and_run_program(program).unwrap();
+    assert!(matches!(
+        egraph.explain_equivalence(&lhs, &rhs),
+        Err(Error::NotEqual(..))
+    ));
+    egraph.run_until_saturated().unwrap();
+
+    let explanation = egraph.explain_equivalence(&lhs, &rhs).unwrap();
+    assert_eq!(explanation.start.to_string(), lhs.to_string());
+    assert_eq!(explanation.steps.len(), 1);
+    assert_eq!(explanation.steps[0].term.to_string(), rhs.to_string());
+    let ProofReason::Congruence(args) = &explanation.steps[0].reason else {
+        panic!("expected congruence, got {:?}", explanation.steps[0].reason)
+    };
+    assert_eq!(args.len(), 1);
+    assert!(matches!(args[0].steps[0].reason, ProofReason::Rule { .. }));
+
+    assert!(matches!(
+        egraph.enable_proofs(),
+        Err(Error::ProofsEnabledTooLate)
+    ));
+
+    // tuples set for a function with a merge expression aren't terms, so when
+    // they collide there's no congruence to explain their outputs being equal
+    let mut egraph = EGraph::default();
+    egraph.enable_proofs().unwrap();
+    egraph
+        .parse_and_run_program(
+            "(datatype Math (Num i64))
+             (function f (Math) Math :merge new)
+             (set (f (Num 1)) (Num 3))
+             (set (f (Num 2)) (Num 4))
+             (union (Num 1) (Num 2))
+             (run 1)
+             (check (= (Num 3) (Num 4)))",
+        )
+        .unwrap();
+    let num = |n: i64| Expr::call("Num", [Expr::lit(n)]);
+    assert!(matches!(
+        egraph.explain_equivalence(&num(3), &num(4)),
+        Err(Error::Unexplainable(..))
+    ));
+}
+
+#[test]
+fn extraction_costs() {
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program(
+            "(datatype Math
+               (Num i64 :cost 0)
+               (Var String)
+               (Add Math Math)
+               (Mul Math Math :cost 4))
+             (function Shl (Math i64) Math :cost 3)
+             (define x (Var \"x\"))
+             (define e (Mul x (Num 2)))
+             (union e (Add x x))
+             (union e (Shl x 1))",
+        )
+        .unwrap();
+    // Mul costs 5, Add costs 3, and Shl costs 4
+    let extract = |egraph: &mut EGraph| egraph.parse_and_run_program("(extract e)").unwrap();
+    assert_eq!(
+        extract(&mut egraph),
+        ["Extracted with cost 3: (Add (Var \"x\") (Var \"x\"))"]
+    );
+
+    struct NoAdding;
+    impl CostFunction for NoAdding {
+        fn cost(&self, ctor: Symbol, child_costs: &[Cost]) -> Cost {
+            let cost = if ctor == "Add".into() { 100 } else { 1 };
+            cost + child_costs.iter().sum::<Cost>()
+        }
+    }
+    egraph.set_cost_function(NoAdding);
+    assert_eq!(
+        extract(&mut egraph),
+        ["Extracted with cost 2: (Shl (Var \"x\") 1)"]
+    );
+}
+
+#[test]
+fn extract_values() {
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program(
+            "(datatype Math (Num i64) (Add Math Math))
+             (function lo (Math) i64 :merge (max old new))
+             (function hi (Math) i64 :merge (min old new))
+             (function name (Math) String)
+             (relation positive (Math))
+             (function pair (Math Math) Math)
+             (define e (Add (Num 1) (Num 2)))
+             (set (lo e) 3)
+             (set (hi e) 3)
+             (positive e)
+             (union (pair e (Num 0)) (Num 7))",
+        )
+        .unwrap();
+
+    let extract = |egraph: &mut EGraph, program| egraph.parse_and_run_program(program).unwrap();
+    assert_eq!(extract(&mut egraph, "(extract (lo e))"), ["Extracted 3"]);
+    assert_eq!(
+        extract(&mut egraph, "(extract (+ 1//2 1//3))"),
+        ["Extracted 5//6"]
+    );
+    assert_eq!(
+        extract(&mut egraph, "(extract (positive e))"),
+        ["Extracted ()"]
+    );
+    // extracting looks values up, without adding them to the database
+    for program in ["(extract (positive (Num 1)))", "(extract (lo (Num 1)))"] {
+        match egraph.parse_and_run_program(program) {
+            Err(Error::NotFoundError(_)) => {}
+            result => panic!("expected {program} not to be found, got {result:?}"),
+        }
+    }
+    assert!(egraph
+        .parse_and_run_program("(check (positive (Num 1)))")
+        .is_err());
+    assert_eq!(
+        extract(&mut egraph, "(extract :with-functions e)"),
+        ["Extracted with cost 3: (Add (Num 1) (Num 2))
+  (= (hi (Add (Num 1) (Num 2))) 3)
+  (= (lo (Add (Num 1) (Num 2))) 3)
+  (= (pair (Add (Num 1) (Num 2)) (Num 0)) (Num 7))
+  (positive (Add (Num 1) (Num 2)))"]
+    );
+    // (Num 0) is in pair too
+    let zero = egraph.eval_closed_expr(&Expr::call("Num", [Expr::lit(0)]));
+    let facts = egraph.extract_function_values(Id::from(zero.unwrap()));
+    assert_eq!(facts.len(), 1);
+}
+
+#[test]
+fn extract_variants() {
+    let mut egraph = EGraph::default();
+    let msgs = egraph
+        .parse_and_run_program(
+            "(datatype Math (Num i64) (Var String) (Add Math Math) (Mul Math Math :cost 2))
+             (define x (Var \"x\"))
+             (define e (Add x x))
+             (union e (Mul x (Num 2)))
+             (union e (Mul (Num 2) x))
+             (union (Num 2) (Add (Num 1) (Num 1)))
+             (extract :variants 2 e)
+             (extract :variants 5 e)",
+        )
+        .unwrap();
+    // the variants past the cheapest use both terms of (Num 2)
+    assert_eq!(
+        msgs[msgs.len() - 2..],
+        [
+            "Extracted 2 variants:
+  cost 3: (Add (Var \"x\") (Var \"x\"))
+  cost 4: (Mul (Var \"x\") (Num 2))",
+            "Extracted 5 variants:
+  cost 3: (Add (Var \"x\") (Var \"x\"))
+  cost 4: (Mul (Num 2) (Var \"x\"))
+  cost 4: (Mul (Var \"x\") (Num 2))
+  cost 6: (Mul (Add (Num 1) (Num 1)) (Var \"x\"))
+  cost 6: (Mul (Var \"x\") (Add (Num 1) (Num 1)))",
+        ]
+    );
+
+    let e = egraph.eval_closed_expr(&Expr::var("e")).unwrap();
+    assert_eq!(egraph.extract_variants(Id::from(e), 1).unwrap()[0].0, 3);
+
+    // a child with a variant that contains the child itself has terms of every size
+    let mut egraph = EGraph::default();
+    let msgs = egraph
+        .parse_and_run_program(
+            "(datatype Expr (Var String) (Zero) (Plus Expr Expr) (Neg Expr))
+             (define a (Var \"a\"))
+             (union a (Plus a (Zero)))
+             (extract :variants 4 (Neg a))",
+        )
+        .unwrap();
+    assert_eq!(
+        msgs[msgs.len() - 1],
+        "Extracted 4 variants:
+  cost 2: (Neg (Var \"a\"))
+  cost 4: (Neg (Plus (Var \"a\") (Zero)))
+  cost 6: (Neg (Plus (Plus (Var \"a\") (Zero)) (Zero)))
+  cost 8: (Neg (Plus (Plus (Plus (Var \"a\") (Zero)) (Zero)) (Zero)))"
+    );
+}
+
+#[test]
+fn extract_dag() {
+    let mut egraph = EGraph::default();
+    let msgs = egraph
+        .parse_and_run_program(
+            "(datatype Math (Num i64) (Var String) (Add Math Math) (Mul Math Math))
+             (define p (Mul (Var \"x\") (Num 3)))
+             (define e (Add p (Num 5)))
+             (union e (Add p p))
+             (extract e)
+             (extract :dag e)
+             (extract :dag :time-limit 0 e)",
+        )
+        .unwrap();
+    // as a tree p costs 3 each time, but as a DAG it's only paid for once
+    assert_eq!(
+        msgs[msgs.len() - 3..],
+        [
+            "Extracted with cost 5: (Add (Mul (Var \"x\") (Num 3)) (Num 5))",
+            "Extracted DAG with cost 4: (let v0 (Mul (Var \"x\") (Num 3)) (Add v0 v0))",
+            "Extracted DAG with cost 4: (let v0 (Mul (Var \"x\") (Num 3)) (Add v0 v0))",
+        ]
+    );
+
+    // the greedy choice for a is (Add (Num 1) (Num 2)), which is cheaper on its own,
+    // but (Mul x x) shares x with b
+    let msgs = egraph
+        .parse_and_run_program(
+            "(define x (Mul (Var \"y\") (Num 7)))
+             (define a (Add (Num 1) (Num 2)))
+             (union a (Mul x x))
+             (define b (Add x x))
+             (extract :dag (Add a b))
+             (extract :dag :time-limit 0 (Add a b))",
+        )
+        .unwrap();
+    assert_eq!(
+        msgs[msgs.len() - 2..],
+        [
+            "Extracted DAG with cost 6: (let v0 (Mul (Var \"y\") (Num 7)) (Add (Mul v0 v0) (Add v0 v0)))",
+            // without time to search
+            "Extracted DAG with cost 8: (let v0 (Mul (Var \"y\") (Num 7)) (Add (Add (Num 1) (Num 2)) (Add v0 v0)))",
+        ]
+    );
+}
+
+#[test]
+fn extract_wide_dag() {
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program("(datatype Math (Num i64) (Add Math Math))")
+        .unwrap();
+    // a balanced tree with 20000 leaves, far too many e-classes to search
+    let mut level: Vec<Expr> = (0..20000)
+        .map(|i| Expr::call("Num", [Expr::lit(i)]))
+        .collect();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [a, b] => Expr::call("Add", [a.clone(), b.clone()]),
+                [a] => a.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    let e = egraph.eval_closed_expr(&level[0]).unwrap();
+    let (cost, expr) = egraph
+        .extract_dag(Id::from(e), std::time::Duration::from_secs(1))
+        .unwrap();
+    assert_eq!(cost, 39999);
+    assert_eq!(expr, level[0]);
+}
+
+#[test]
+fn unextractable() {
+    let mut egraph = EGraph::default();
+    let msgs = egraph
+        .parse_and_run_program(
+            "(datatype Math
+               (Num i64)
+               (Add Math Math)
+               (Mul Math Math)
+               (Hidden Math :unextractable))
+             (function Proof (Math Math) Math :unextractable)
+             (define two (Num 2))
+             (define e (Add two two))
+             (union e (Hidden two))
+             (union e (Proof two two))
+             (union e (Mul two (Num 2)))
+             (extract :variants 5 e)
+             (extract :only (Num Mul) e)
+             (extract :dag :only (Num Mul) e)",
+        )
+        .unwrap();
+    assert_eq!(
+        msgs[msgs.len() - 3..],
+        [
+            "Extracted 2 variants:
+  cost 3: (Add (Num 2) (Num 2))
+  cost 3: (Mul (Num 2) (Num 2))",
+            "Extracted with cost 3: (Mul (Num 2) (Num 2))",
+            "Extracted DAG with cost 2: (let v0 (Num 2) (Mul v0 v0))",
+        ]
+    );
+
+    // an e-class with only unextractable terms
+    egraph
+        .parse_and_run_program("(define h (Hidden (Hidden (Num 2))))")
+        .unwrap();
+    for program in [
+        "(extract h)",
+        "(extract :variants 2 h)",
+        "(extract :dag h)",
+        "(extract :only (Add) e)",
+    ] {
+        match egraph.parse_and_run_program(program) {
+            Err(Error::NotExtractable(_)) => {}
+            result => panic!("expected no extractable term for {program}, got {result:?}"),
+        }
+    }
+    let h = egraph.eval_closed_expr(&Expr::var("h")).unwrap();
+    assert!(matches!(
+        egraph.extract(Id::from(h)),
+        Err(Error::NotExtractable(_))
+    ));
+    assert!(matches!(
+        egraph.parse_and_run_program("(extract :only (Nope) e)"),
+        Err(Error::TypeError(TypeError::Unbound(..)))
+    ));
+}
+
+#[test]
+fn short_equalities() {
+    let mut egraph = EGraph::default();
+    egraph
+        .parse_and_run_program("(datatype Math (Num i64)) (Num 1)")
+        .unwrap();
+    let one = Fact::Eq(Span::default(), vec![Expr::call("Num", [Expr::lit(1)])]);
+    let too_few = |e: &TypeError| matches!(e, TypeError::TooFewExprs(_));
+
+    match egraph.check_with(&Default::default(), &one) {
+        Err(Error::TypeError(e)) if too_few(&e) => {}
+        result => panic!("expected too few expressions, got {result:?}"),
+    }
+    match egraph.union_exprs(&Default::default(), &[]) {
+        Err(Error::TypeError(e)) if too_few(&e) => {}
+        result => panic!("expected too few expressions, got {result:?}"),
+    }
+    // in a query, and in a negation
+    let num = Fact::Fact(Expr::call("Num", [Expr::var("x")]));
+    for body in [
+        vec![one.clone()],
+        vec![num, Fact::Not(Span::default(), Box::new(one))],
+    ] {
+        let rule = ast::Rule {
+            span: Span::default(),
+            head: vec![],
+            body,
+            ruleset: "".into(),
+        };
+        match egraph.add_rule(rule) {
+            Err(Error::TypeErrors(errors)) if errors.iter().any(too_few) => {}
+            result => panic!("expected too few expressions, got {result:?}"),
+        }
+    }
+}
+
+#[test]
+fn declare_constructors() {
+    let mut egraph = EGraph::default();
+    egraph.declare_sort("Math").unwrap();
+    let i64 = Type::NumType(NumType::I64);
+    egraph
+        .declare_constructor("Num", vec![i64], "Math")
+        .unwrap();
+    let hidden = Variant {
+        span: Span::default(),
+        name: "Hidden".into(),
+        types: vec![Type::Sort("Math".into())],
+        cost: None,
+        unextractable: true,
+    };
+    egraph.declare_variant(hidden, "Math").unwrap();
+    let msgs = egraph
+        .parse_and_run_program(
+            "(define e (Hidden (Num 1)))
+             (union e (Num 2))
+             (extract :variants 2 e)",
+        )
+        .unwrap();
+    assert_eq!(
+        msgs[msgs.len() - 1],
+        "Extracted 1 variants:\n  cost 1: (Num 2)"
+    );
+}
@@ -7,6 +7,11 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 #[wasm_bindgen]
 pub fn run_program(input: &str) -> String {
     let mut egraph = egg_smol::EGraph::default();
+    // keep runaway programs from taking the whole tab down with them
+    egraph.set_run_config(egg_smol::RunConfig {
+        node_limit: Some(1_000_000),
+        ..Default::default()
+    });
     match egraph.parse_and_run_program(input) {
         Ok(outputs) => {
             log::info!("egg ok, {} outputs", outputs.len());